@@ -1,4 +1,4 @@
-use std::cmp::min;
+use std::cmp::{min, Ordering};
 use std::ffi::{CStr, FromBytesWithNulError, OsStr};
 use std::fmt::{Arguments, Debug, Display, Formatter, Write};
 use std::ops::{Deref, DerefMut};
@@ -140,6 +140,13 @@ impl Utf8CString {
     pub fn new() -> Self {
         Self::default()
     }
+
+    // Invalid UTF-8 sequences are replaced with U+FFFD, mainly for logging purposes
+    pub fn from_bytes_lossy(s: &[u8]) -> Self {
+        let mut buf = Self::from(String::with_capacity(s.len() + 1));
+        buf.push_lossy(s);
+        buf
+    }
 }
 
 impl AsUtf8CStr for Utf8CString {
@@ -293,6 +300,10 @@ pub enum StrErr {
     CStrError(#[from] FromBytesWithNulError),
     #[error("argument is null")]
     NullPointerError,
+    #[error("string contains an interior nul byte")]
+    InteriorNulError,
+    #[error("suffix contains a path separator")]
+    PathSeparatorError,
 }
 
 // UTF-8 validated + null terminated string slice
@@ -317,12 +328,21 @@ impl Utf8CStr {
         unsafe { Ok(mem::transmute::<&mut [u8], &mut Utf8CStr>(buf)) }
     }
 
+    // The string is assumed to not contain any nul bytes. If it does, the C string view
+    // silently ends at the first one; use from_string_checked for untrusted input.
     pub fn from_string(s: &mut String) -> &mut Utf8CStr {
         let buf = s.nul_terminate();
         // SAFETY: the null byte is explicitly added to the buffer
         unsafe { mem::transmute(buf) }
     }
 
+    pub fn from_string_checked(s: &mut String) -> Result<&mut Utf8CStr, StrErr> {
+        if s.as_bytes().contains(&b'\0') {
+            return Err(StrErr::InteriorNulError);
+        }
+        Ok(Self::from_string(s))
+    }
+
     #[inline(always)]
     pub unsafe fn from_bytes_unchecked(buf: &[u8]) -> &Utf8CStr {
         mem::transmute(buf)
@@ -345,6 +365,22 @@ impl Utf8CStr {
         &self.0
     }
 
+    // Length in bytes, excluding the null terminator
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.0.len() - 1
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline(always)]
+    pub fn byte_len_with_nul(&self) -> usize {
+        self.0.len()
+    }
+
     #[inline(always)]
     pub fn as_ptr(&self) -> *const c_char {
         self.0.as_ptr().cast()
@@ -374,6 +410,34 @@ impl Utf8CStr {
         // SAFETY: The length of the slice is at least 1 due to null termination check
         unsafe { str::from_utf8_unchecked_mut(self.0.get_unchecked_mut(..self.0.len() - 1)) }
     }
+
+    pub fn strip_prefix(&self, prefix: &str) -> Option<&Utf8CStr> {
+        let tail = self.as_str().strip_prefix(prefix)?;
+        let start = self.0.len() - 1 - tail.len();
+        // SAFETY: the tail starts at a char boundary and keeps the original null terminator
+        unsafe { Some(Self::from_bytes_unchecked(self.0.get_unchecked(start..))) }
+    }
+
+    // Stripping a suffix cannot preserve the null terminator, so a plain &str is returned
+    #[inline(always)]
+    pub fn strip_suffix(&self, suffix: &str) -> Option<&str> {
+        self.as_str().strip_suffix(suffix)
+    }
+
+    // Returns a sibling path with suffix appended, e.g. a scratch file that is renamed
+    // over the original once fully written. The result stays in the same directory.
+    pub fn with_suffix(&self, suffix: &str) -> Result<Utf8CString, StrErr> {
+        if suffix.contains('\0') {
+            return Err(StrErr::InteriorNulError);
+        }
+        if suffix.contains('/') {
+            return Err(StrErr::PathSeparatorError);
+        }
+        let mut s = String::with_capacity(self.len() + suffix.len() + 1);
+        s.push_str(self);
+        s.push_str(suffix);
+        Ok(Utf8CString::from(s))
+    }
 }
 
 impl Deref for Utf8CStr {
@@ -485,6 +549,15 @@ impl<'a> DerefMut for FsPathBuf<'a> {
     }
 }
 
+impl<'a> TryFrom<&'a mut String> for &'a mut Utf8CStr {
+    type Error = StrErr;
+
+    #[inline(always)]
+    fn try_from(s: &'a mut String) -> Result<Self, Self::Error> {
+        Utf8CStr::from_string_checked(s)
+    }
+}
+
 // Boilerplate trait implementations
 
 macro_rules! impl_str {
@@ -555,6 +628,55 @@ macro_rules! impl_str {
                 self == other.as_cstr()
             }
         }
+        impl<$($g)*> PartialEq<OsStr> for $t {
+            #[inline(always)]
+            fn eq(&self, other: &OsStr) -> bool {
+                OsStr::new(self.as_str()) == other
+            }
+        }
+        impl<$($g)*> PartialEq<$t> for OsStr {
+            #[inline(always)]
+            fn eq(&self, other: &$t) -> bool {
+                self == OsStr::new(other.as_str())
+            }
+        }
+        impl<$($g)*> PartialEq<Path> for $t {
+            #[inline(always)]
+            fn eq(&self, other: &Path) -> bool {
+                Path::new(self.as_str()) == other
+            }
+        }
+        impl<$($g)*> PartialEq<$t> for Path {
+            #[inline(always)]
+            fn eq(&self, other: &$t) -> bool {
+                self == Path::new(other.as_str())
+            }
+        }
+        impl<$($g)*> PartialOrd<OsStr> for $t {
+            #[inline(always)]
+            fn partial_cmp(&self, other: &OsStr) -> Option<Ordering> {
+                OsStr::new(self.as_str()).partial_cmp(other)
+            }
+        }
+        impl<$($g)*> PartialOrd<$t> for OsStr {
+            #[inline(always)]
+            fn partial_cmp(&self, other: &$t) -> Option<Ordering> {
+                self.partial_cmp(OsStr::new(other.as_str()))
+            }
+        }
+        // Paths are ordered by components, consistent with the PartialEq impls above
+        impl<$($g)*> PartialOrd<Path> for $t {
+            #[inline(always)]
+            fn partial_cmp(&self, other: &Path) -> Option<Ordering> {
+                Path::new(self.as_str()).partial_cmp(other)
+            }
+        }
+        impl<$($g)*> PartialOrd<$t> for Path {
+            #[inline(always)]
+            fn partial_cmp(&self, other: &$t) -> Option<Ordering> {
+                self.partial_cmp(Path::new(other.as_str()))
+            }
+        }
         impl<T: AsRef<Utf8CStr>, $($g)*> PartialEq<T> for $t {
             #[inline(always)]
             fn eq(&self, other: &T) -> bool {
@@ -663,3 +785,71 @@ macro_rules! raw_cstr {
         $crate::cstr!($($str)*).as_ptr()
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_suffix_checks_suffix() {
+        let path = cstr!("/data/adb/magisk.db");
+        let tmp = path.with_suffix(".tmp").unwrap();
+        assert_eq!(tmp.as_str(), "/data/adb/magisk.db.tmp");
+        assert_eq!(tmp.as_bytes_with_nul(), b"/data/adb/magisk.db.tmp\0");
+        assert_eq!(path.with_suffix("").unwrap().as_str(), path.as_str());
+        assert!(matches!(
+            path.with_suffix("/x"),
+            Err(StrErr::PathSeparatorError)
+        ));
+        assert!(matches!(
+            path.with_suffix(".tmp\0x"),
+            Err(StrErr::InteriorNulError)
+        ));
+    }
+
+    #[test]
+    fn strip_prefix_keeps_terminator() {
+        let path = cstr!("/system/bin/sh");
+        let tail = path.strip_prefix("/system/").unwrap();
+        assert_eq!(tail.as_str(), "bin/sh");
+        assert_eq!(tail.as_bytes_with_nul(), b"bin/sh\0");
+        assert_eq!(path.strip_prefix("").unwrap().as_str(), path.as_str());
+        assert_eq!(path.strip_prefix(path).unwrap().as_bytes_with_nul(), b"\0");
+        assert!(path.strip_prefix("/vendor").is_none());
+        // Multi-byte chars are stripped at char boundaries
+        let tail = cstr!("äö").strip_prefix("ä").unwrap();
+        assert_eq!(tail.as_bytes_with_nul(), "ö\0".as_bytes());
+    }
+
+    #[test]
+    fn from_string_checked_rejects_nul() {
+        let mut s = String::from("abc");
+        let c = Utf8CStr::from_string_checked(&mut s).unwrap();
+        assert_eq!(c.as_bytes_with_nul(), b"abc\0");
+        let mut s = String::from("a\0bc");
+        assert!(matches!(
+            Utf8CStr::from_string_checked(&mut s),
+            Err(StrErr::InteriorNulError)
+        ));
+        let mut s = String::new();
+        assert!(Utf8CStr::from_string_checked(&mut s).unwrap().is_empty());
+    }
+
+    #[test]
+    fn compare_with_path() {
+        let path = cstr!("/system/bin");
+        assert!(*path == *Path::new("/system/bin/"));
+        assert!(*Path::new("/system//bin") == *path);
+        assert!(*path == *OsStr::new("/system/bin"));
+        assert!(*OsStr::new("/system/bin") == *path);
+        assert!(*path < *Path::new("/system/bin/sh"));
+        assert!(*Path::new("/system") < *path);
+        assert!(*path > *OsStr::new("/system"));
+        assert!(*OsStr::new("/vendor") > *path);
+        // Paths compare by components, so a trailing separator is not significant
+        assert_eq!(
+            path.partial_cmp(Path::new("/system/bin/")),
+            Some(Ordering::Equal)
+        );
+    }
+}
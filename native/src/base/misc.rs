@@ -1,12 +1,14 @@
+use std::cmp::min;
+use std::ffi::CStr;
 use std::fmt::Arguments;
 use std::io::Write;
 use std::process::exit;
-use std::{fmt, io, slice, str};
+use std::{fmt, io, mem, slice, str};
 
 use argh::EarlyExit;
 use libc::c_char;
 
-use crate::{ffi, StrErr, Utf8CStr};
+use crate::{error, ffi, StrErr, Utf8CStr, Utf8CString};
 
 pub fn errno() -> &'static mut i32 {
     unsafe { &mut *libc::__errno() }
@@ -32,6 +34,26 @@ pub unsafe fn slice_from_ptr_mut<'a, T>(buf: *mut T, len: usize) -> &'a mut [T]
     }
 }
 
+// Copy as many bytes as possible from src to dest, returns the number of bytes copied.
+// Unlike strings, the result is not null terminated.
+pub fn copy_bytes(dest: &mut [u8], src: &[u8]) -> usize {
+    let len = min(src.len(), dest.len());
+    dest[..len].copy_from_slice(&src[..len]);
+    len
+}
+
+// Reinterpret bytes as a slice of T. Returns None if the length of buf is not a
+// multiple of the size of T, or buf is not properly aligned for T.
+// The caller has to make sure any bit pattern is a valid T.
+#[inline]
+pub unsafe fn slice_from_bytes<T: Copy>(buf: &[u8]) -> Option<&[T]> {
+    let sz = mem::size_of::<T>();
+    if sz == 0 || buf.len() % sz != 0 || buf.as_ptr().align_offset(mem::align_of::<T>()) != 0 {
+        return None;
+    }
+    Some(slice_from_ptr(buf.as_ptr().cast(), buf.len() / sz))
+}
+
 // Check libc return value and map to Result
 pub trait LibcReturn
 where
@@ -122,7 +144,16 @@ impl<T: AsMut<[u8]> + ?Sized> MutBytesExt for T {
 pub fn map_args(argc: i32, argv: *const *const c_char) -> Result<Vec<&'static str>, StrErr> {
     unsafe { slice::from_raw_parts(argv, argc as usize) }
         .iter()
-        .map(|s| unsafe { Utf8CStr::from_ptr(*s) }.map(|s| s.as_str()))
+        .map(|s| {
+            unsafe { Utf8CStr::from_ptr(*s) }
+                .map(|s| s.as_str())
+                .inspect_err(|_| {
+                    if !s.is_null() {
+                        let arg = unsafe { CStr::from_ptr(*s) }.to_bytes();
+                        error!("Invalid argument: {}", Utf8CString::from_bytes_lossy(arg));
+                    }
+                })
+        })
         .collect()
 }
 
@@ -161,3 +192,40 @@ impl<T: Write> fmt::Write for FmtAdaptor<'_, T> {
         self.0.write_fmt(args).map_err(|_| fmt::Error)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slice_from_bytes_alignment() {
+        let words = [0x0102_0304_u32, 0x0506_0708];
+        let bytes = unsafe { slice::from_raw_parts(words.as_ptr().cast::<u8>(), 8) };
+
+        let all = unsafe { slice_from_bytes::<u32>(bytes) }.unwrap();
+        assert_eq!(all, words);
+        assert_eq!(
+            unsafe { slice_from_bytes::<u32>(&bytes[..0]) },
+            Some(&[][..])
+        );
+        // Misaligned
+        assert_eq!(unsafe { slice_from_bytes::<u32>(&bytes[1..5]) }, None);
+        // Not a multiple of the size
+        assert_eq!(unsafe { slice_from_bytes::<u32>(&bytes[..3]) }, None);
+        assert_eq!(unsafe { slice_from_bytes::<u32>(&bytes[..7]) }, None);
+        // Zero sized types never fit
+        assert_eq!(unsafe { slice_from_bytes::<()>(bytes) }, None);
+    }
+
+    #[test]
+    fn copy_bytes_truncates() {
+        let mut dest = [0u8; 4];
+        assert_eq!(copy_bytes(&mut dest, b"ab"), 2);
+        assert_eq!(&dest, b"ab\0\0");
+        assert_eq!(copy_bytes(&mut dest, b"abcdef"), 4);
+        assert_eq!(&dest, b"abcd");
+        assert_eq!(copy_bytes(&mut dest, b""), 0);
+        assert_eq!(&dest, b"abcd");
+        assert_eq!(copy_bytes(&mut [], b"abc"), 0);
+    }
+}
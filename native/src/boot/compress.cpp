@@ -317,12 +317,12 @@ protected:
         ENCODE_LZMA
     } mode;
 
-    lzma_strm(mode_t mode, out_strm_ptr &&base) :
+    lzma_strm(mode_t mode, out_strm_ptr &&base, uint32_t preset = 9) :
             filter_out_stream(std::move(base)), mode(mode), strm(LZMA_STREAM_INIT), outbuf{0} {
         lzma_options_lzma opt;
 
         // Initialize preset
-        lzma_lzma_preset(&opt, 9);
+        lzma_lzma_preset(&opt, preset);
         lzma_filter filters[] = {
             { .id = LZMA_FILTER_LZMA2, .options = &opt },
             { .id = LZMA_VLI_UNKNOWN, .options = nullptr },
@@ -374,12 +374,14 @@ public:
 
 class xz_encoder : public lzma_strm {
 public:
-    explicit xz_encoder(out_strm_ptr &&base) : lzma_strm(ENCODE_XZ, std::move(base)) {}
+    explicit xz_encoder(out_strm_ptr &&base, uint32_t level = 9)
+            : lzma_strm(ENCODE_XZ, std::move(base), level) {}
 };
 
 class lzma_encoder : public lzma_strm {
 public:
-    explicit lzma_encoder(out_strm_ptr &&base) : lzma_strm(ENCODE_LZMA, std::move(base)) {}
+    explicit lzma_encoder(out_strm_ptr &&base, uint32_t level = 9)
+            : lzma_strm(ENCODE_LZMA, std::move(base), level) {}
 };
 
 class LZ4F_decoder : public filter_out_stream {
@@ -740,8 +742,8 @@ bool decompress(rust::Slice<const uint8_t> buf, int fd) {
     return true;
 }
 
-bool xz(rust::Slice<const uint8_t> buf, rust::Vec<uint8_t> &out) {
-    auto strm = get_encoder(XZ, make_unique<rust_vec_stream>(out));
+bool xz(rust::Slice<const uint8_t> buf, uint32_t level, rust::Vec<uint8_t> &out) {
+    auto strm = make_unique<xz_encoder>(make_unique<rust_vec_stream>(out), level);
     if (!strm->write(buf.data(), buf.length())) {
         return false;
     }
@@ -760,3 +762,41 @@ bool unxz(rust::Slice<const uint8_t> buf, rust::Vec<uint8_t> &out) {
     }
     return true;
 }
+
+bool lzma(rust::Slice<const uint8_t> buf, uint32_t level, rust::Vec<uint8_t> &out) {
+    auto strm = make_unique<lzma_encoder>(make_unique<rust_vec_stream>(out), level);
+    if (!strm->write(buf.data(), buf.length())) {
+        return false;
+    }
+    return true;
+}
+
+bool unlzma(rust::Slice<const uint8_t> buf, rust::Vec<uint8_t> &out) {
+    // Raw LZMA has no reliable magic, so the format is not checked here
+    auto strm = get_decoder(LZMA, make_unique<rust_vec_stream>(out));
+    if (!strm->write(buf.data(), buf.length())) {
+        return false;
+    }
+    return true;
+}
+
+bool unxz_fd(rust::Slice<const uint8_t> buf, int fd) {
+    format_t type = check_fmt(buf.data(), buf.length());
+    if (type != XZ) {
+        LOGE("Input file is not in xz format!\n");
+        return false;
+    }
+    auto strm = get_decoder(XZ, make_unique<fd_stream>(fd));
+    if (!strm->write(buf.data(), buf.length())) {
+        return false;
+    }
+    return true;
+}
+
+bool unlzma_fd(rust::Slice<const uint8_t> buf, int fd) {
+    auto strm = get_decoder(LZMA, make_unique<fd_stream>(fd));
+    if (!strm->write(buf.data(), buf.length())) {
+        return false;
+    }
+    return true;
+}
@@ -10,5 +10,9 @@ out_strm_ptr get_decoder(format_t type, out_strm_ptr &&base);
 void compress(const char *method, const char *infile, const char *outfile);
 void decompress(char *infile, const char *outfile);
 bool decompress(rust::Slice<const uint8_t> buf, int fd);
-bool xz(rust::Slice<const uint8_t> buf, rust::Vec<uint8_t> &out);
+bool xz(rust::Slice<const uint8_t> buf, uint32_t level, rust::Vec<uint8_t> &out);
 bool unxz(rust::Slice<const uint8_t> buf, rust::Vec<uint8_t> &out);
+bool lzma(rust::Slice<const uint8_t> buf, uint32_t level, rust::Vec<uint8_t> &out);
+bool unlzma(rust::Slice<const uint8_t> buf, rust::Vec<uint8_t> &out);
+bool unxz_fd(rust::Slice<const uint8_t> buf, int fd);
+bool unlzma_fd(rust::Slice<const uint8_t> buf, int fd);
@@ -1,35 +1,91 @@
 #![allow(clippy::useless_conversion)]
 
+use std::borrow::Cow;
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env;
+use std::ffi::CStr;
 use std::fmt::{Display, Formatter};
+use std::fs;
 use std::fs::File;
-use std::io::{Read, Write};
-use std::mem::size_of;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem::{size_of, take};
+use std::ops::{Deref, DerefMut, RangeInclusive};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 use std::process::exit;
 use std::str;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Instant, SystemTime};
 
 use argh::FromArgs;
 use bytemuck::{from_bytes, Pod, Zeroable};
 use num_traits::cast::AsPrimitive;
 use size::{Base, Size, Style};
+use thiserror::Error;
 
 use base::libc::{
-    c_char, dev_t, gid_t, major, makedev, minor, mknod, mode_t, uid_t, O_CLOEXEC, O_CREAT,
+    c_char, dev_t, gid_t, major, makedev, minor, mknod, mode_t, pipe2, uid_t, O_CLOEXEC, O_CREAT,
     O_RDONLY, O_TRUNC, O_WRONLY, S_IFBLK, S_IFCHR, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG, S_IRGRP,
     S_IROTH, S_IRUSR, S_IWGRP, S_IWOTH, S_IWUSR, S_IXGRP, S_IXOTH, S_IXUSR,
 };
 use base::{
-    log_err, map_args, BytesExt, EarlyExitExt, FsPath, LoggedResult, MappedFile, ResultExt,
-    Utf8CStr, Utf8CStrBufArr, Utf8CStrWrite, WriteExt,
+    log_err, map_args, BytesExt, Directory, EarlyExitExt, FsPath, LibcReturn, LoggedResult,
+    MappedFile, ResultExt, Utf8CStr, Utf8CStrBufArr, Utf8CStrWrite, WriteExt,
 };
 
 use crate::check_env;
-use crate::ffi::{unxz, xz};
+use crate::ffi::{lzma, unlzma, unlzma_fd, unxz, unxz_fd, xz};
+use crate::json::Json;
 use crate::patch::{patch_encryption, patch_verity};
+use crate::sign::sha256_hash;
 
 #[derive(FromArgs)]
 struct CpioCli {
+    #[argh(switch)]
+    checksum: bool,
+    #[argh(option, from_str_fn(parse_out_format))]
+    out_format: Option<CpioFormat>,
+    #[argh(
+        option,
+        default = "InodeMode::Sequential",
+        from_str_fn(parse_inode_mode)
+    )]
+    inode: InodeMode,
+    #[argh(switch)]
+    backslash: bool,
+    #[argh(option, default = "1")]
+    threads: usize,
+    #[argh(option)]
+    compress_level: Option<u32>,
+    #[argh(option, default = "Mtime::Fixed(0)", from_str_fn(parse_mtime))]
+    mtime: Mtime,
+    #[argh(switch)]
+    no_trailer: bool,
+    #[argh(option)]
+    prefix: Option<String>,
+    #[argh(option)]
+    script: Option<String>,
+    #[argh(switch)]
+    keep_going: bool,
+    #[argh(switch)]
+    stats: bool,
+    #[argh(switch)]
+    strict: bool,
+    #[argh(switch)]
+    lossy_names: bool,
+    #[argh(switch)]
+    progress: bool,
+    #[argh(switch)]
+    interactive: bool,
+    #[argh(option, default = "CpioLimits::default()", from_str_fn(parse_limits))]
+    limits: CpioLimits,
+    #[argh(option)]
+    backup_suffix: Option<String>,
+    #[argh(switch)]
+    lenient: bool,
     #[argh(positional)]
     file: String,
     #[argh(positional)]
@@ -51,31 +107,108 @@ enum CpioAction {
     Exists(Exists),
     Backup(Backup),
     Remove(Remove),
+    PruneEmpty(PruneEmpty),
     Move(Move),
+    Rename(Rename),
     Extract(Extract),
+    Export(Export),
+    DumpHex(DumpHex),
     MakeDir(MakeDir),
     Link(Link),
+    ReadLink(ReadLink),
+    Relink(Relink),
     Add(Add),
     List(List),
+    Grep(Grep),
+    Find(Find),
+    Truncate(Truncate),
+    ReplaceAll(ReplaceAll),
+    NormalizeOwner(NormalizeOwner),
+    Sanitize(Sanitize),
+    OwnerMap(OwnerMap),
+    ChecksumVerify(ChecksumVerify),
+    Sony(Sony),
+    AddRaw(AddRaw),
+    Info(Info),
+    Stat(Stat),
+    VerifyTree(VerifyTree),
+    Validate(Validate),
+    Split(Split),
+    Merge(Merge),
+    CopyFrom(CopyFrom),
+    Diff(Diff),
+    Pack(Pack),
+    Unpack(Unpack),
+    HasBackup(HasBackup),
+}
+
+impl CpioAction {
+    // Whether the action may change the archive, and so needs a copy to roll back to
+    fn mutates(&self) -> bool {
+        !matches!(
+            self,
+            CpioAction::Test(_)
+                | CpioAction::Patch(Patch {
+                    print_plan: true,
+                    ..
+                })
+                | CpioAction::Exists(_)
+                | CpioAction::Remove(Remove { dry_run: true, .. })
+                | CpioAction::Extract(_)
+                | CpioAction::Export(_)
+                | CpioAction::DumpHex(_)
+                | CpioAction::ReadLink(_)
+                | CpioAction::List(_)
+                | CpioAction::Grep(_)
+                | CpioAction::Find(_)
+                | CpioAction::ChecksumVerify(_)
+                | CpioAction::Info(_)
+                | CpioAction::Stat(_)
+                | CpioAction::VerifyTree(_)
+                | CpioAction::Validate(_)
+                | CpioAction::Split(_)
+                | CpioAction::Merge(Merge { dry_run: true, .. })
+                | CpioAction::Diff(_)
+                | CpioAction::Unpack(_)
+                | CpioAction::HasBackup(_)
+        )
+    }
 }
 
 #[derive(FromArgs)]
 #[argh(subcommand, name = "test")]
-struct Test {}
+struct Test {
+    #[argh(switch, short = 'v')]
+    verbose: bool,
+    #[argh(switch)]
+    names: bool,
+}
 
 #[derive(FromArgs)]
 #[argh(subcommand, name = "restore")]
-struct Restore {}
+struct Restore {
+    #[argh(option)]
+    source: Option<String>,
+}
 
 #[derive(FromArgs)]
 #[argh(subcommand, name = "patch")]
-struct Patch {}
+struct Patch {
+    #[argh(option)]
+    keep_verity: Option<bool>,
+    #[argh(option)]
+    keep_forceencrypt: Option<bool>,
+    #[argh(switch)]
+    print_plan: bool,
+}
 
 #[derive(FromArgs)]
 #[argh(subcommand, name = "exists")]
 struct Exists {
     #[argh(positional, arg_name = "entry")]
     path: String,
+    #[argh(switch, short = 'i')]
+    ignore_case: bool,
 }
 
 #[derive(FromArgs)]
@@ -94,6 +227,15 @@ struct Remove {
     path: String,
     #[argh(switch, short = 'r')]
     recursive: bool,
+    #[argh(switch, short = 'n')]
+    dry_run: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "prune-empty")]
+struct PruneEmpty {
+    #[argh(positional, default = r#"String::from("/")"#)]
+    path: String,
 }
 
 #[derive(FromArgs)]
@@ -103,15 +245,74 @@ struct Move {
     from: String,
     #[argh(positional, arg_name = "dest")]
     to: String,
+    #[argh(switch, short = 'f')]
+    force: bool,
+    #[argh(option)]
+    after: Option<String>,
+    #[argh(option)]
+    before: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "rename")]
+struct Rename {
+    #[argh(positional, arg_name = "glob")]
+    from: String,
+    #[argh(positional, arg_name = "template")]
+    to: String,
 }
 
 #[derive(FromArgs)]
 #[argh(subcommand, name = "extract")]
 struct Extract {
+    #[argh(switch, short = 't')]
+    list: bool,
+    #[argh(option, default = "0o755", from_str_fn(parse_mode))]
+    dir_mode: mode_t,
+    #[argh(switch)]
+    flatten: bool,
+    #[argh(switch)]
+    verify: bool,
+    #[argh(switch)]
+    sparse: bool,
+    #[argh(option)]
+    include: Vec<String>,
+    #[argh(option)]
+    exclude: Vec<String>,
+    #[argh(option, long = "type", from_str_fn(parse_type))]
+    types: Vec<CpioType>,
     #[argh(positional, greedy)]
     paths: Vec<String>,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "export")]
+struct Export {
+    #[argh(option, default = "0")]
+    offset: u64,
+    #[argh(option)]
+    bytes: Option<u64>,
+    #[argh(switch)]
+    strict: bool,
+    #[argh(positional, arg_name = "entry")]
+    path: String,
+    #[argh(positional, arg_name = "outfile")]
+    out: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "dump-hex")]
+struct DumpHex {
+    #[argh(option, default = "0")]
+    offset: u64,
+    #[argh(option)]
+    bytes: Option<u64>,
+    #[argh(switch)]
+    strict: bool,
+    #[argh(positional, arg_name = "entry")]
+    path: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "mkdir")]
 struct MakeDir {
@@ -119,6 +320,8 @@ struct MakeDir {
     mode: mode_t,
     #[argh(positional, arg_name = "entry")]
     dir: String,
+    #[argh(switch, short = 'p')]
+    parents: bool,
 }
 
 #[derive(FromArgs)]
@@ -130,15 +333,50 @@ struct Link {
     dst: String,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "readlink")]
+struct ReadLink {
+    #[argh(positional, arg_name = "entry")]
+    path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "relink")]
+struct Relink {
+    #[argh(positional, arg_name = "entry")]
+    path: String,
+    #[argh(positional, arg_name = "target")]
+    target: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "add")]
 struct Add {
-    #[argh(positional, from_str_fn(parse_mode))]
-    mode: mode_t,
+    #[argh(positional, from_str_fn(parse_add_mode))]
+    mode: AddMode,
     #[argh(positional, arg_name = "entry")]
     path: String,
     #[argh(positional, arg_name = "infile")]
     file: String,
+    #[argh(option)]
+    max_size: Option<u64>,
+    #[argh(switch, short = 'p')]
+    parents: bool,
+    #[argh(option)]
+    after: Option<String>,
+    #[argh(option)]
+    before: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "add-raw")]
+struct AddRaw {
+    #[argh(positional, from_str_fn(parse_mode))]
+    mode: mode_t,
+    #[argh(positional, arg_name = "entry")]
+    path: String,
+    #[argh(positional)]
+    hex: String,
 }
 
 #[derive(FromArgs)]
@@ -148,42 +386,500 @@ struct List {
     path: String,
     #[argh(switch, short = 'r')]
     recursive: bool,
+    #[argh(option)]
+    max_depth: Option<usize>,
+    #[argh(option, default = "ListSort::Name", from_str_fn(parse_sort))]
+    sort: ListSort,
+    #[argh(switch, short = 'i')]
+    inode: bool,
+    #[argh(switch)]
+    absolute: bool,
+    #[argh(switch)]
+    relative: bool,
+    #[argh(switch)]
+    resolve_ids: bool,
+    #[argh(switch)]
+    bytes: bool,
+    #[argh(option, long = "type", from_str_fn(parse_type))]
+    types: Vec<CpioType>,
+}
+
+#[derive(Copy, Clone)]
+enum AddMode {
+    Fixed(mode_t),
+    // The permissions of the source file
+    Source,
+}
+
+#[derive(Copy, Clone)]
+enum Mtime {
+    Fixed(u64),
+    SourceDateEpoch,
+}
+
+// Attributes excluded from diff; file types and data are always compared
+#[derive(Copy, Clone, Default)]
+struct DiffIgnore {
+    mode: bool,
+    uid: bool,
+    gid: bool,
+}
+
+#[derive(Copy, Clone)]
+enum ListSort {
+    Name,
+    Size,
+    Mode,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "grep")]
+struct Grep {
+    #[argh(positional)]
+    pattern: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "find")]
+struct Find {
+    #[argh(switch, short = 'c')]
+    count: bool,
+    #[argh(option, long = "type", from_str_fn(parse_type))]
+    types: Vec<CpioType>,
+    #[argh(positional, arg_name = "glob")]
+    pattern: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "truncate")]
+struct Truncate {
+    #[argh(positional, arg_name = "entry")]
+    path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "replace-all")]
+struct ReplaceAll {
+    #[argh(switch)]
+    allow_empty: bool,
+    #[argh(positional, arg_name = "glob")]
+    pattern: String,
+    #[argh(positional, arg_name = "infile")]
+    file: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "normalize-owner")]
+struct NormalizeOwner {
+    #[argh(positional, default = "0")]
+    uid: uid_t,
+    #[argh(positional, default = "0")]
+    gid: gid_t,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "sanitize")]
+struct Sanitize {}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "owner-map")]
+struct OwnerMap {
+    #[argh(switch)]
+    strict: bool,
+    #[argh(positional)]
+    file: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "checksum-verify")]
+struct ChecksumVerify {
+    #[argh(switch)]
+    strict: bool,
+    #[argh(positional, arg_name = "manifest")]
+    file: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "info")]
+struct Info {
+    #[argh(switch)]
+    json: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "stat")]
+struct Stat {
+    #[argh(switch)]
+    json: bool,
+    #[argh(positional, arg_name = "entry")]
+    path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "verify-tree")]
+struct VerifyTree {}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "validate")]
+struct Validate {}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "split")]
+struct Split {}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "merge")]
+struct Merge {
+    #[argh(
+        option,
+        default = "MergeStrategy::Overwrite",
+        from_str_fn(parse_merge_strategy)
+    )]
+    strategy: MergeStrategy,
+    #[argh(option)]
+    mount_point: Option<String>,
+    #[argh(switch)]
+    rebase_absolute: bool,
+    #[argh(switch, short = 'n')]
+    dry_run: bool,
+    #[argh(positional, arg_name = "other")]
+    file: String,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum MergeStrategy {
+    Overwrite,
+    KeepExisting,
+    Error,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "copy-from")]
+struct CopyFrom {
+    #[argh(positional, arg_name = "other")]
+    file: String,
+    #[argh(positional, arg_name = "entry")]
+    path: String,
+    #[argh(positional, arg_name = "dest")]
+    dest: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "diff")]
+struct Diff {
+    #[argh(
+        option,
+        default = "DiffIgnore::default()",
+        from_str_fn(parse_diff_ignore)
+    )]
+    ignore: DiffIgnore,
+    #[argh(switch)]
+    json: bool,
+    #[argh(option)]
+    path: Option<String>,
+    #[argh(positional, arg_name = "other")]
+    file: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "pack")]
+struct Pack {
+    #[argh(positional, arg_name = "dir")]
+    dir: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "unpack")]
+struct Unpack {
+    #[argh(option, default = "0o755", from_str_fn(parse_mode))]
+    dir_mode: mode_t,
+    #[argh(positional, arg_name = "dir")]
+    dir: String,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "has-backup")]
+struct HasBackup {}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "sony")]
+struct Sony {}
+
 fn print_cpio_usage() {
     eprintln!(
-        r#"Usage: magiskboot cpio <incpio> [commands...]
+        r#"Usage: magiskboot cpio [options] <incpio> [commands...]
 
 Do cpio commands to <incpio> (modifications are done in-place).
 Each command is a single argument; add quotes for each command.
+xz compressed <incpio> is detected and written back compressed.
+Set env variable CPIO_COMP=xz|lzma to force the compression method of incpio.
+
+Options:
+  --checksum
+    Write the output in newc-crc (070702) format with per-entry data checksums
+  --out-format FORMAT
+    Write the output in FORMAT: newc (070701), crc (070702),
+    odc (portable ASCII, 070707), or bin (old binary, little-endian)
+    Writing fails if any value does not fit the fields of FORMAT
+    By default, the format of <incpio> is kept (newc for new archives)
+  --inode MODE
+    Choose how inode numbers are written:
+    seq (default): sequential numbers starting from 300000
+    keep: the original numbers of loaded entries
+    hash: numbers derived from the entry contents
+    path: distinct numbers derived from the entry paths, stable across runs
+          Hardlinked entries share the number of the first path among them
+    In odc and bin, hash and path numbers wrap around to fit the narrower
+    fields, and keep renumbers the entries in order if any number does not fit
+    With keep and path, entries loaded as hardlinks are written as hardlinks
+    unless their data was replaced, or they came from another archive
+  --backslash
+    Treat '\' in entry paths given to commands as a path separator
+  --threads N
+    Use N threads to construct entries when loading <incpio> (1 by default)
+  --compress-level N
+    Compression level used when writing a compressed <incpio>
+    xz, lzma: 0-9 (6 by default)
+    Fails before running any command if the level is out of range, or if
+    <incpio> is not compressed
+  --mtime TIME
+    Set the modification time of all entries to TIME, which can be:
+    zero (default), source-date-epoch (from env variable SOURCE_DATE_EPOCH),
+    or seconds since the epoch. Entry times are not kept when loading.
+  --no-trailer
+    Do not write the TRAILER!!! entry, producing a segment that can be
+    concatenated with other archives before a single final trailer
+  --prefix DIR
+    Write all entries under the directory DIR, creating DIR and its parents
+    with permissions 755. Entries are not renamed within incpio itself.
+    Fails if an entry is already DIR or within it
+  --script FILE
+    Run the commands in FILE, one per line, after the ones given as arguments
+    Blank lines and lines starting with '#' are skipped
+  --keep-going
+    Continue with the next command when a command fails, undoing any changes
+    it made. incpio is still written; the failed commands are then listed
+    one per line as 'failed<TAB>COMMAND<TAB>ERROR' and the exit status is
+    non-zero, also when a later command (e.g. ls, test) ends the run early
+  --stats
+    Print the time spent loading, running each command, and dumping, along
+    with the number of entries and total data size to stderr at the end
+    Commands that exit early (e.g. ls, test) skip the summary
+  --strict
+    Fail if incpio has unexpected non-zero data after a trailer
+    Zero padding after a trailer is always accepted
+  --lenient
+    When a header of incpio is damaged, skip ahead to the next newc or odc
+    header instead of failing. Each skipped region is reported and counted
+    as unexpected data (see info), so it also fails --strict
+  --lossy-names
+    Accept entry names that are not valid UTF-8. Such names are shown and
+    matched with U+FFFD in place of invalid bytes, and the original bytes
+    are written back when the entry is kept under the same name
+  --backup-suffix SUFFIX
+    Before writing incpio, rename the existing file to incpio + SUFFIX
+    (e.g. .bak), so the previous archive can be restored if needed
+  --progress
+    Periodically print the number of processed entries to stderr while
+    loading, dumping, and extracting all entries
+  --interactive
+    After running the given commands, read further commands from stdin,
+    one per line. Besides the commands below, the following are accepted:
+    save (write incpio), exit (write incpio and stop), quit (stop without
+    writing), undo (revert the last modifying command), and help
+    End of input is treated as quit
+  --limits LIMITS
+    Fail to load incpio if it exceeds any of the comma separated LIMITS:
+    entries=N (number of entries), data=N (total bytes of entry data).
+    Both are unlimited by default. Also accepts scan=N (bytes searched
+    after a trailer for the next concatenated archive, 65536 by default),
+    beyond which the rest of incpio is treated as unexpected data
+    The limits only apply to incpio, not to archives read by commands
 
 Supported commands:
-  exists ENTRY
+  exists [-i] ENTRY
     Return 0 if ENTRY exists, else return 1
-  ls [-r] [PATH]
+    Specify [-i] to ignore case (scans all entries)
+  ls [-r] [-i] [--max-depth N] [--sort KEY] [--absolute|--relative]
+     [--resolve-ids] [--bytes] [--type TYPE] [PATH]
     List PATH ("/" by default); specify [-r] to list recursively
-  rm [-r] ENTRY
+    Specify [--max-depth N] to list recursively up to N levels below PATH
+    Specify [--sort KEY] to sort by name (default), size (largest first), or mode
+    Specify [-i] to print the inode number and link count of each entry
+    Specify [--absolute] to print names with a leading '/', or [--relative]
+    to print names relative to PATH
+    Specify [--resolve-ids] to print well-known Android uids/gids as names
+    Specify [--bytes] to print sizes as exact byte counts instead of kB, MB, etc.
+    Specify [--type TYPE] to only list entries of TYPE, which is one of
+    f (regular file), d (directory), l (symlink), b (block), or c (char
+    device); it can be repeated to list several types
+  info [--json]
+    Print the format, compression, size (uncompressed and as stored), and
+    number of entries of incpio, and the number of unexpected bytes found
+    after trailers
+    Specify [--json] to print them as a single JSON object
+  stat [--json] ENTRY
+    Print the type, permissions, owner, device numbers, size, and inode
+    number of ENTRY, and the target if it is a symlink
+    Specify [--json] to print them as a single JSON object
+  split
+    Write each concatenated archive within incpio to a numbered file
+    e.g. ramdisk.cpio -> ramdisk.0.cpio, ramdisk.1.cpio, ...
+    The files are compressed with the same method as incpio
+  verify-tree
+    Check for dangling symlinks and entries without a parent directory
+    Return 0 if no problems are found, else return 1
+  validate
+    Check that all entries are well-formed: canonical paths, valid modes and
+    types, no data on directories or devices, and sizes that fit in cpio
+    Print each problem found; return 0 if there are none, else return 1
+  grep PATTERN
+    Search the contents of all regular files for PATTERN
+    Print ENTRY:OFFSET for each match; return 0 if found, else return 1
+  find [-c] [--type TYPE] GLOB
+    Print all entries matching GLOB ('*' matches any string, '?' any character)
+    Specify [-c] to only print the number of matching entries
+    Specify [--type TYPE] to only match entries of TYPE (see ls)
+    Return 0 if any entry matches, else return 1
+  rm [-r] [-n] ENTRY
     Remove ENTRY, specify [-r] to remove recursively
-  mkdir MODE ENTRY
+    Specify [-n] to only print the entries that would be removed; incpio is
+    left untouched and the remaining commands are not run
+  prune-empty [PATH]
+    Remove empty directories within PATH ("/" by default), including PATH
+    Directories that are symlink targets are kept
+  mkdir [-p] MODE ENTRY
     Create directory ENTRY with permissions MODE
+    Fails if a parent of ENTRY exists but is not a directory
+    Specify [-p] to create missing parents with permissions 755
   ln TARGET ENTRY
     Create a symlink to TARGET with the name ENTRY
-  mv SOURCE DEST
-    Move SOURCE to DEST
-  add MODE ENTRY INFILE
+  readlink ENTRY
+    Print the target of the symlink ENTRY
+  relink ENTRY TARGET
+    Change the target of the symlink ENTRY to TARGET
+  mv [-f] [--after ENTRY|--before ENTRY] SOURCE DEST
+    Move SOURCE to DEST; if DEST is a directory, move SOURCE into DEST
+    A directory is moved with its contents, but not into itself
+    Specify [-f] to overwrite existing entries
+    [--after] and [--before] are rejected, as entries are always written
+    sorted by name
+  rename GLOB TEMPLATE
+    Rename all entries matching GLOB; each '*' in TEMPLATE is replaced with
+    the text matched by the corresponding '*' in GLOB, e.g.
+    rename 'overlay/*.orig' 'overlay/*' strips the .orig suffixes
+    Fails without renaming anything if two entries would get the same name,
+    or if an entry would replace one that is not renamed
+  truncate ENTRY
+    Clear the contents of the regular file ENTRY, keeping its metadata
+  replace-all [--allow-empty] GLOB INFILE
+    Replace the contents of every regular file matching GLOB with INFILE,
+    keeping their metadata. Fails if no entry matches, unless [--allow-empty]
+  normalize-owner [UID GID]
+    Set the owner of all entries to UID:GID (0:0 by default)
+  sanitize
+    Prepare incpio for distribution: set the owner of all entries to 0:0 and
+    drop their original inode numbers, so --inode keep cannot leak them
+    Symlinks to host paths (e.g. /home/...) are reported but left unchanged
+    Modification times are always written as set by --mtime
+  owner-map [--strict] FILE
+    Remap the owner of all entries with the OLD:NEW id pairs in FILE
+    Prefix a line with u: or g: to only map uids or gids, e.g. u:1000:1001
+    Specify [--strict] to fail if a non-zero id is not in FILE
+  checksum-verify [--strict] MANIFEST
+    Check entries against the SHA-256 sums in MANIFEST, which has lines of
+    'HASH  ENTRY' as written by sha256sum. Print ENTRY: FAILED for each
+    mismatch and ENTRY: MISSING for each absent entry; return 0 if all match,
+    else return 1. Specify [--strict] to also fail on regular files that are
+    not listed in MANIFEST, printed as ENTRY: UNLISTED
+  add [-p] [--max-size BYTES] [--after ENTRY|--before ENTRY] MODE ENTRY INFILE
     Add INFILE as ENTRY with permissions MODE; replaces ENTRY if exists
-  extract [ENTRY OUT]
+    MODE can be '-' to use the permissions of INFILE
+    Fails if a parent of ENTRY exists but is not a directory
+    Specify [-p] to create missing parents with permissions 755
+    INFILE cannot be larger than BYTES, or 4GiB (the newc limit) by default
+    INFILE is read when incpio is written, or when a later command needs it
+    [--after] and [--before] are rejected, as entries are always written
+    sorted by name
+  add-raw MODE ENTRY HEX
+    Add the bytes decoded from HEX as ENTRY with permissions MODE
+  extract [-t] [--dir-mode MODE] [ENTRY OUT]
     Extract ENTRY to OUT, or extract all entries to current directory
-  test
-    Test the cpio's status. Return values:
-    0:stock    1:Magisk    2:unsupported
-  patch
+    Specify [-t] to only list the paths that would be written
+    Specify [--dir-mode MODE] to set the permissions of created parent
+    directories (755 by default)
+  extract --flatten [-t] [--dir-mode MODE] [DIR]
+    Extract all regular files into DIR (current directory by default) using
+    only their basenames; duplicated basenames are numbered, e.g. init.rc.1
+  extract [--include GLOB] [--exclude GLOB] [--type TYPE] [--verify] [--sparse] ...
+    Options accepted by both forms of extract above
+    When extracting all entries, specify [--include GLOB] to only extract
+    matching entries, and [--exclude GLOB] to skip matching entries
+    Both can be repeated; '*' matches any string and '?' any character
+    Specify [--type TYPE] to only extract entries of TYPE (see ls)
+    Specify [--verify] to check that each extracted file matches its entry
+    Specify [--sparse] to leave holes for blocks of zeros in regular files
+  export [--offset N] [--bytes N] [--strict] ENTRY OUTFILE
+    Write the contents of the regular file ENTRY to OUTFILE
+    Specify [--offset N] to skip the first N bytes, and [--bytes N] to write
+    at most N bytes. The range is clamped to the size of ENTRY; specify
+    [--strict] to fail if it does not fit instead
+  dump-hex [--offset N] [--bytes N] [--strict] ENTRY
+    Print a hex and ASCII dump of the regular file ENTRY, 16 bytes per line
+    The range options are the same as for export
+  test [-v] [--names]
+    Test the cpio's status. Return value is 0 or OR-ed of the following:
+    0x1:Magisk    0x2:unsupported    0x4:Sony init (init.real)
+    0x8:stock ramdisk backup
+    Specify [-v] to print the reason for each detected status
+    Specify [--names] to also print the name of each detected status
+  sony
+    Remove Sony specific modifications (sbin/ric, init.real) from the ramdisk
+  patch [--keep-verity BOOL] [--keep-forceencrypt BOOL] [--print-plan]
     Apply ramdisk patches
-    Configure with env variables: KEEPVERITY KEEPFORCEENCRYPT
+    Configure with options, or env variables: KEEPVERITY KEEPFORCEENCRYPT
+    Options take precedence over env variables; both default to false
+    Specify [--print-plan] to only list the entries that would be changed
+  merge [--strategy STRATEGY] OTHER
+    Merge all entries of the cpio archive OTHER into incpio
+    STRATEGY decides what happens when an entry exists in both archives:
+    overwrite (default): use the entry from OTHER
+    keep: keep the existing entry
+    error: fail the command
+    Directories existing in both archives are always kept
+    Specify [--mount-point DIR] to merge the entries of OTHER under DIR;
+    relative symlink targets that would escape DIR are rewritten to point
+    to the same entry, and [--rebase-absolute] also moves absolute targets
+    under DIR. Specify [-n] to only print the rewritten symlinks
+  diff [--ignore ATTRS] [--path DIR] [--json] OTHER
+    Compare incpio with the cpio archive OTHER. Entries only in incpio are
+    printed as '-<TAB>ENTRY', entries only in OTHER as '+<TAB>ENTRY', and
+    changed entries as 'M<TAB>ENTRY<TAB>ATTRS'. Return 0 if there are no
+    differences, else return 1
+    Specify [--ignore ATTRS] to skip the comma separated attributes in ATTRS:
+    mode, uid, gid. File types and contents are always compared
+    Specify [--path DIR] to only compare DIR and the entries under it
+    Specify [--json] to print the differences as a single JSON object
+  copy-from OTHER ENTRY [DEST]
+    Copy ENTRY with all its metadata from the cpio archive OTHER into incpio
+    as DEST (ENTRY by default); replaces DEST if exists
+  pack DIR
+    Replace all entries of incpio with the contents of the directory DIR
+    Modes, owners, symlinks, and device nodes are stored as found on disk
+    Files are read one at a time when incpio is written
+  unpack [--dir-mode MODE] DIR
+    Extract all entries of incpio under the directory DIR, the reverse of pack
+    Symlinks are created after all other entries, so no entry is written
+    through a symlink from incpio
+  has-backup
+    Return 0 if incpio contains a stock ramdisk backup, else return 1
   backup ORIG [-n]
     Create ramdisk backups from ORIG, specify [-n] to skip compression
-  restore
+  restore [--source FILE]
     Restore ramdisk from ramdisk backup stored within incpio
+    Specify [--source FILE] to restore from the stock ramdisk FILE instead;
+    only entries Magisk added, replaced or patched are restored, and entries
+    only found in FILE are reported but not added
 "#
     )
 }
@@ -207,648 +903,5423 @@ struct CpioHeader {
     check: [u8; 8],
 }
 
-struct Cpio {
-    entries: BTreeMap<String, Box<CpioEntry>>,
+#[derive(Debug, Error)]
+pub enum CpioError {
+    #[error("no such entry {0}")]
+    NotFound(String),
+    #[error("bad cpio format: {0}")]
+    BadFormat(&'static str),
+    #[error("cpio data is truncated")]
+    Truncated,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("failed to {0} {1}: {2}")]
+    IoAt(&'static str, String, #[source] io::Error),
+    #[error("unsupported: {0}")]
+    Unsupported(&'static str),
+    #[error("invalid path {0}")]
+    InvalidPath(String),
+    #[error("file is too large ({0} bytes)")]
+    TooLarge(u64),
+    #[error("invalid entry {0}: {1}")]
+    InvalidEntry(String, &'static str),
+    #[error("{1} of entry {0} does not fit in {2} format")]
+    FieldOverflow(String, &'static str, CpioFormat),
+    #[error("{1} {2} of entry {0} is not mapped")]
+    Unmapped(String, &'static str, u32),
+    #[error("extracted file {0} does not match the archive")]
+    Mismatch(String),
+    #[error("entry {0} already exists")]
+    Exists(String),
+    #[error("invalid compression level {0} for {1}")]
+    InvalidLevel(u32, CpioCompression),
+    #[error("archive exceeds the limit of {1} {0}")]
+    LimitExceeded(&'static str, usize),
+    #[error("invalid {0}: {1}")]
+    Invalid(&'static str, String),
 }
 
-struct CpioEntry {
-    mode: mode_t,
-    uid: uid_t,
-    gid: gid_t,
-    rdevmajor: dev_t,
-    rdevminor: dev_t,
-    data: Vec<u8>,
+pub type CpioResult<T> = Result<T, CpioError>;
+
+// Name the operation and file of I/O errors, e.g. "failed to create /x: Permission denied"
+trait IoContext<T> {
+    fn io_context(self, op: &'static str, path: &str) -> CpioResult<T>;
 }
 
-impl Cpio {
-    fn new() -> Self {
-        Self {
-            entries: BTreeMap::new(),
-        }
+impl<T> IoContext<T> for io::Result<T> {
+    fn io_context(self, op: &'static str, path: &str) -> CpioResult<T> {
+        self.map_err(|e| CpioError::IoAt(op, path.to_string(), e))
+    }
+}
+
+impl<T> IoContext<T> for CpioResult<T> {
+    fn io_context(self, op: &'static str, path: &str) -> CpioResult<T> {
+        self.map_err(|e| match e {
+            CpioError::Io(e) => CpioError::IoAt(op, path.to_string(), e),
+            e => e,
+        })
     }
+}
 
-    fn load_from_data(data: &[u8]) -> LoggedResult<Self> {
-        let mut cpio = Cpio::new();
-        let mut pos = 0_usize;
-        while pos < data.len() {
-            let hdr_sz = size_of::<CpioHeader>();
-            let hdr = from_bytes::<CpioHeader>(&data[pos..(pos + hdr_sz)]);
-            if &hdr.magic != b"070701" {
-                return Err(log_err!("invalid cpio magic"));
-            }
-            pos += hdr_sz;
-            let name_sz = x8u(&hdr.namesize)? as usize;
-            let name = Utf8CStr::from_bytes(&data[pos..(pos + name_sz)])?.to_string();
-            pos += name_sz;
-            pos = align_4(pos);
-            if name == "." || name == ".." {
-                continue;
-            }
-            if name == "TRAILER!!!" {
-                match data[pos..].find(b"070701") {
-                    Some(x) => pos += x,
-                    None => break,
-                }
-                continue;
+#[derive(Copy, Clone)]
+enum InodeMode {
+    Sequential,
+    Keep,
+    Hash,
+    Path,
+}
+
+// Differences between two archives as (kind, entry, changed attributes), where kind is
+// '-' for removed, '+' for added, and 'M' for modified entries
+struct CpioDiff<'a> {
+    changes: Vec<(char, &'a str, Vec<&'static str>)>,
+}
+
+impl CpioDiff<'_> {
+    fn print(&self) {
+        for (kind, name, attrs) in &self.changes {
+            if attrs.is_empty() {
+                println!("{}\t{}", kind, name);
+            } else {
+                println!("{}\t{}\t{}", kind, name, attrs.join(","));
             }
-            let file_sz = x8u(&hdr.filesize)? as usize;
-            let entry = Box::new(CpioEntry {
-                mode: x8u(&hdr.mode)?.as_(),
-                uid: x8u(&hdr.uid)?.as_(),
-                gid: x8u(&hdr.gid)?.as_(),
-                rdevmajor: x8u(&hdr.rdevmajor)?.as_(),
-                rdevminor: x8u(&hdr.rdevminor)?.as_(),
-                data: data[pos..(pos + file_sz)].to_vec(),
-            });
-            pos += file_sz;
-            cpio.entries.insert(name, entry);
-            pos = align_4(pos);
         }
-        Ok(cpio)
     }
 
-    fn load_from_file(path: &Utf8CStr) -> LoggedResult<Self> {
-        eprintln!("Loading cpio: [{}]", path);
-        let file = MappedFile::open(path)?;
-        Self::load_from_data(file.as_ref())
+    // JSON: {"added": [entry...], "removed": [entry...],
+    //        "modified": [{"name": entry, "attrs": [attr...]}...]}
+    // Entries are names without a leading '/', attrs are the ones printed without --json
+    fn to_json(&self) -> Json {
+        let names = |kind: char| {
+            let names = self.changes.iter().filter(|c| c.0 == kind).map(|c| c.1);
+            Json::from(names.collect::<Vec<_>>())
+        };
+        let modified = self.changes.iter().filter(|c| c.0 == 'M');
+        let modified = modified.map(|(_, name, attrs)| {
+            Json::Object(vec![
+                ("name", (*name).into()),
+                ("attrs", attrs.clone().into()),
+            ])
+        });
+        Json::Object(vec![
+            ("added", names('+')),
+            ("removed", names('-')),
+            ("modified", Json::Array(modified.collect())),
+        ])
     }
+}
 
-    fn dump(&self, path: &str) -> LoggedResult<()> {
-        eprintln!("Dumping cpio: [{}]", path);
-        let mut file = File::create(path)?;
-        let mut pos = 0usize;
-        let mut inode = 300000i64;
-        for (name, entry) in &self.entries {
-            pos += file.write(
-                format!(
-                    "070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
-                    inode,
-                    entry.mode,
-                    entry.uid,
-                    entry.gid,
-                    1,
-                    0,
-                    entry.data.len(),
-                    0,
-                    0,
-                    entry.rdevmajor,
-                    entry.rdevminor,
-                    name.len() + 1,
-                    0
-                ).as_bytes(),
-            )?;
-            pos += file.write(name.as_bytes())?;
-            pos += file.write(&[0])?;
-            file.write_zeros(align_4(pos) - pos)?;
-            pos = align_4(pos);
-            pos += file.write(&entry.data)?;
-            file.write_zeros(align_4(pos) - pos)?;
-            pos = align_4(pos);
-            inode += 1;
+// Resource limits applied while loading an archive, which may come from an untrusted source
+#[derive(Copy, Clone)]
+pub struct CpioLimits {
+    max_entries: usize,
+    max_data: usize,
+    // How far past the zero padding after a trailer to search for the next archive
+    trailer_scan: usize,
+}
+
+impl Default for CpioLimits {
+    fn default() -> Self {
+        CpioLimits {
+            max_entries: usize::MAX,
+            max_data: usize::MAX,
+            trailer_scan: 64 * 1024,
         }
-        pos += file.write(
-            format!("070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
-                inode, 0o755, 0, 0, 1, 0, 0, 0, 0, 0, 0, 11, 0
-            ).as_bytes()
-        )?;
-        pos += file.write("TRAILER!!!\0".as_bytes())?;
-        file.write_zeros(align_4(pos) - pos)?;
-        Ok(())
     }
+}
 
-    fn rm(&mut self, path: &str, recursive: bool) {
-        let path = norm_path(path);
-        if self.entries.remove(&path).is_some() {
-            eprintln!("Removed entry [{}]", path);
-        }
-        if recursive {
-            let path = path + "/";
-            self.entries.retain(|k, _| {
-                if k.starts_with(&path) {
-                    eprintln!("Removed entry [{}]", k);
-                    false
-                } else {
-                    true
-                }
-            })
-        }
+impl CpioLimits {
+    pub fn max_entries(mut self, n: usize) -> Self {
+        self.max_entries = n;
+        self
     }
 
-    fn extract_entry(&self, path: &str, out: &mut String) -> LoggedResult<()> {
-        let entry = self
-            .entries
-            .get(path)
-            .ok_or_else(|| log_err!("No such file"))?;
-        eprintln!("Extracting entry [{}] to [{}]", path, out);
+    // Total size of the data of all entries
+    pub fn max_data(mut self, n: usize) -> Self {
+        self.max_data = n;
+        self
+    }
 
-        let out = Utf8CStr::from_string(out);
-        let out = FsPath::from(out);
+    pub fn trailer_scan(mut self, n: usize) -> Self {
+        self.trailer_scan = n;
+        self
+    }
+}
 
-        let mut buf = Utf8CStrBufArr::default();
+#[derive(Copy, Clone)]
+struct LoadOptions {
+    threads: usize,
+    progress: bool,
+    limits: CpioLimits,
+    // Skip damaged headers instead of failing
+    lenient: bool,
+    // Compression to assume instead of detecting it
+    compression: Option<CpioCompression>,
+}
 
-        // Make sure its parent directories exist
-        if out.parent(&mut buf) {
-            FsPath::from(&buf).mkdirs(0o755)?;
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            threads: 1,
+            progress: false,
+            limits: CpioLimits::default(),
+            lenient: false,
+            compression: None,
         }
+    }
+}
 
-        let mode: mode_t = (entry.mode & 0o777).into();
+// How much a stream is read ahead at once when looking for something
+const STREAM_CHUNK: usize = 64 * 1024;
 
-        match entry.mode & S_IFMT {
-            S_IFDIR => out.mkdir(mode)?,
-            S_IFREG => {
-                let mut file = out.create(O_CREAT | O_TRUNC | O_WRONLY | O_CLOEXEC, mode)?;
-                file.write_all(&entry.data)?;
-            }
-            S_IFLNK => {
-                buf.clear();
-                buf.push_str(str::from_utf8(entry.data.as_slice())?);
-                FsPath::from(&buf).symlink_to(out)?;
-            }
-            S_IFBLK | S_IFCHR => {
-                let dev = makedev(entry.rdevmajor.try_into()?, entry.rdevminor.try_into()?);
-                unsafe { mknod(out.as_ptr().cast(), entry.mode, dev) };
+// The bytes of an archive being loaded, either all in memory or streamed from a decoder
+trait LoadInput<'a> {
+    // The bytes at the current position, at least n of them unless the input ends first
+    fn peek(&mut self, n: usize) -> CpioResult<&[u8]>;
+    // Skip n bytes, or up to the end of the input
+    fn skip(&mut self, n: usize) -> CpioResult<()>;
+    // Take exactly n bytes, borrowed from the input if possible
+    fn take(&mut self, n: usize) -> CpioResult<Cow<'a, [u8]>>;
+    // The number of bytes consumed so far
+    fn offset(&self) -> usize;
+
+    // Skip the padding up to the next multiple of align
+    fn align(&mut self, align: usize) -> CpioResult<()> {
+        let pos = self.offset();
+        self.skip(align_after(pos, 0, align)? - pos)
+    }
+
+    // Position of pat within the next `within` bytes, ignoring the first `from` of them
+    fn find(&mut self, from: usize, within: usize, pat: &[u8]) -> CpioResult<Option<usize>> {
+        let mut want = within.min(from.saturating_add(STREAM_CHUNK));
+        loop {
+            let data = self.peek(want)?;
+            let end = data.len().min(within);
+            if let Some(x) = data.get(from..end).and_then(|d| d.find(pat)) {
+                return Ok(Some(from + x));
             }
-            _ => {
-                return Err(log_err!("unknown entry type"));
+            if end == within || data.len() < want {
+                return Ok(None);
             }
+            want = within.min(want.saturating_mul(2).max(data.len() + 1));
         }
+    }
+}
+
+struct SliceInput<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SliceInput<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        SliceInput { data, pos: 0 }
+    }
+}
+
+impl<'a> LoadInput<'a> for SliceInput<'a> {
+    fn peek(&mut self, _: usize) -> CpioResult<&[u8]> {
+        Ok(&self.data[self.pos..])
+    }
+
+    fn skip(&mut self, n: usize) -> CpioResult<()> {
+        self.pos = self.data.len().min(self.pos.saturating_add(n));
         Ok(())
     }
 
-    fn extract(&self, path: Option<&mut String>, out: Option<&mut String>) -> LoggedResult<()> {
-        let path = path.map(|s| norm_path(s.as_str()));
-        if let (Some(path), Some(out)) = (&path, out) {
-            return self.extract_entry(path, out);
-        } else {
-            for path in self.entries.keys() {
-                if path == "." || path == ".." {
-                    continue;
-                }
-                self.extract_entry(path, &mut path.clone())?;
-            }
+    fn take(&mut self, n: usize) -> CpioResult<Cow<'a, [u8]>> {
+        let data = slice_at(self.data, self.pos, n)?;
+        self.pos += n;
+        Ok(Cow::Borrowed(data))
+    }
+
+    fn offset(&self) -> usize {
+        self.pos
+    }
+}
+
+// Only the bytes peeked at are buffered; taken bytes are read straight into their own buffer
+struct StreamInput<R> {
+    reader: R,
+    buf: Vec<u8>,
+    start: usize,
+    offset: usize,
+}
+
+impl<R: Read> StreamInput<R> {
+    fn new(reader: R) -> Self {
+        StreamInput {
+            reader,
+            buf: Vec::new(),
+            start: 0,
+            offset: 0,
         }
-        Ok(())
     }
 
-    fn exists(&self, path: &str) -> bool {
-        self.entries.contains_key(&norm_path(path))
+    // Discard everything left in the stream
+    fn finish(mut self) -> io::Result<()> {
+        io::copy(&mut self.reader, &mut io::sink())?;
+        Ok(())
     }
+}
 
-    fn add(&mut self, mode: mode_t, path: &str, file: &mut String) -> LoggedResult<()> {
-        if path.ends_with('/') {
-            return Err(log_err!("path cannot end with / for add"));
+impl<'a, R: Read> LoadInput<'a> for StreamInput<R> {
+    fn peek(&mut self, n: usize) -> CpioResult<&[u8]> {
+        if self.buf.len() - self.start < n {
+            self.buf.drain(..self.start);
+            self.start = 0;
+            let want = (n - self.buf.len()).max(STREAM_CHUNK);
+            (&mut self.reader)
+                .take(want as u64)
+                .read_to_end(&mut self.buf)?;
         }
-        let file = Utf8CStr::from_string(file);
-        let file = FsPath::from(&file);
-        let attr = file.get_attr()?;
+        Ok(&self.buf[self.start..])
+    }
 
-        let mut content = Vec::<u8>::new();
-        let rdevmajor: dev_t;
-        let rdevminor: dev_t;
+    fn skip(&mut self, n: usize) -> CpioResult<()> {
+        let buffered = self.buf.len() - self.start;
+        if n <= buffered {
+            self.start += n;
+            self.offset += n;
+        } else {
+            self.buf.clear();
+            self.start = 0;
+            let skipped = io::copy(
+                &mut (&mut self.reader).take((n - buffered) as u64),
+                &mut io::sink(),
+            )?;
+            self.offset += buffered + skipped as usize;
+        }
+        Ok(())
+    }
 
-        // Treat symlinks as regular files as symlinks are created by the 'ln TARGET ENTRY' command
-        let mode = if attr.is_file() || attr.is_symlink() {
-            rdevmajor = 0;
-            rdevminor = 0;
-            file.open(O_RDONLY | O_CLOEXEC)?.read_to_end(&mut content)?;
-            mode | S_IFREG
+    fn take(&mut self, n: usize) -> CpioResult<Cow<'a, [u8]>> {
+        let buffered = self.buf.len() - self.start;
+        let mut data = self.buf[self.start..][..n.min(buffered)].to_vec();
+        if n <= buffered {
+            self.start += n;
         } else {
-            rdevmajor = unsafe { major(attr.st.st_rdev.as_()) }.as_();
-            rdevminor = unsafe { minor(attr.st.st_rdev.as_()) }.as_();
-            if attr.is_block_device() {
-                mode | S_IFBLK
-            } else if attr.is_char_device() {
-                mode | S_IFCHR
-            } else {
-                return Err(log_err!("unsupported file type"));
+            self.buf.clear();
+            self.start = 0;
+            // The size comes from an untrusted header, let the buffer grow with what is read
+            (&mut self.reader)
+                .take((n - buffered) as u64)
+                .read_to_end(&mut data)?;
+            if data.len() < n {
+                return Err(CpioError::Truncated);
             }
-        };
-
-        self.entries.insert(
-            norm_path(path),
-            Box::new(CpioEntry {
-                mode,
-                uid: 0,
-                gid: 0,
-                rdevmajor,
-                rdevminor,
-                data: content,
-            }),
-        );
-        eprintln!("Add file [{}] ({:04o})", path, mode);
-        Ok(())
+        }
+        self.offset += n;
+        Ok(Cow::Owned(data))
     }
 
-    fn mkdir(&mut self, mode: mode_t, dir: &str) {
-        self.entries.insert(
-            norm_path(dir),
-            Box::new(CpioEntry {
-                mode: mode | S_IFDIR,
-                uid: 0,
-                gid: 0,
-                rdevmajor: 0,
-                rdevminor: 0,
-                data: vec![],
-            }),
-        );
-        eprintln!("Create directory [{}] ({:04o})", dir, mode);
+    fn offset(&self) -> usize {
+        self.offset
     }
+}
 
-    fn ln(&mut self, src: &str, dst: &str) {
-        self.entries.insert(
-            norm_path(dst),
-            Box::new(CpioEntry {
-                mode: S_IFLNK,
-                uid: 0,
-                gid: 0,
-                rdevmajor: 0,
-                rdevminor: 0,
-                data: norm_path(src).as_bytes().to_vec(),
-            }),
-        );
-        eprintln!("Create symlink [{}] -> [{}]", dst, src);
+// Periodically report the number of processed entries to stderr
+struct Progress {
+    enabled: bool,
+    label: &'static str,
+    total: Option<usize>,
+    count: usize,
+}
+
+impl Progress {
+    const INTERVAL: usize = 1000;
+
+    fn new(enabled: bool, label: &'static str, total: Option<usize>) -> Self {
+        Progress {
+            enabled,
+            label,
+            total,
+            count: 0,
+        }
     }
 
-    fn mv(&mut self, from: &str, to: &str) -> LoggedResult<()> {
-        let entry = self
-            .entries
-            .remove(&norm_path(from))
-            .ok_or_else(|| log_err!("no such entry {}", from))?;
-        self.entries.insert(norm_path(to), entry);
-        eprintln!("Move [{}] -> [{}]", from, to);
-        Ok(())
+    fn tick(&mut self) {
+        self.count += 1;
+        if self.count % Self::INTERVAL == 0 {
+            self.report();
+        }
     }
 
-    fn ls(&self, path: &str, recursive: bool) {
-        let path = norm_path(path);
-        let path = if path.is_empty() {
-            path
-        } else {
-            "/".to_string() + path.as_str()
-        };
-        for (name, entry) in &self.entries {
-            let p = "/".to_string() + name.as_str();
-            if !p.starts_with(&path) {
-                continue;
-            }
-            let p = p.strip_prefix(&path).unwrap();
-            if !p.is_empty() && !p.starts_with('/') {
-                continue;
-            }
-            if !recursive && !p.is_empty() && p.matches('/').count() > 1 {
-                continue;
-            }
-            println!("{}\t{}", entry, name);
+    fn report(&self) {
+        if !self.enabled {
+            return;
+        }
+        match self.total {
+            Some(total) => eprintln!("{}: {}/{} entries", self.label, self.count, total),
+            None => eprintln!("{}: {} entries", self.label, self.count),
         }
     }
 }
 
-const MAGISK_PATCHED: i32 = 1 << 0;
-const UNSUPPORTED_CPIO: i32 = 1 << 1;
+struct ExtractOptions {
+    list: bool,
+    dir_mode: mode_t,
+    verify: bool,
+    sparse: bool,
+    progress: bool,
+}
 
-impl Cpio {
-    fn patch(&mut self) {
-        let keep_verity = check_env("KEEPVERITY");
-        let keep_force_encrypt = check_env("KEEPFORCEENCRYPT");
-        eprintln!(
-            "Patch with flag KEEPVERITY=[{}] KEEPFORCEENCRYPT=[{}]",
-            keep_verity, keep_force_encrypt
-        );
-        self.entries.retain(|name, entry| {
-            let fstab = (!keep_verity || !keep_force_encrypt)
-                && entry.mode & S_IFMT == S_IFREG
-                && !name.starts_with(".backup")
-                && !name.starts_with("twrp")
-                && !name.starts_with("recovery")
-                && name.starts_with("fstab");
-            if !keep_verity {
-                if fstab {
-                    eprintln!("Found fstab file [{}]", name);
-                    let len = patch_verity(entry.data.as_mut_slice());
-                    if len != entry.data.len() {
-                        entry.data.resize(len, 0);
-                    }
-                } else if name == "verity_key" {
-                    return false;
-                }
-            }
-            if !keep_force_encrypt && fstab {
-                let len = patch_encryption(entry.data.as_mut_slice());
-                if len != entry.data.len() {
-                    entry.data.resize(len, 0);
-                }
-            }
-            true
-        });
+struct EntryFilter<'a> {
+    include: &'a [String],
+    exclude: &'a [String],
+    types: &'a [CpioType],
+}
+
+impl EntryFilter<'_> {
+    // Entries have to match any include pattern (if there are any), then no exclude pattern
+    fn matches(&self, name: &str, entry: &CpioEntry) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| glob_match(p, name)))
+            && !self.exclude.iter().any(|p| glob_match(p, name))
+            && type_matches(self.types, entry)
     }
+}
 
-    fn test(&self) -> i32 {
-        for file in [
-            "sbin/launch_daemonsu.sh",
-            "sbin/su",
-            "init.xposed.rc",
-            "boot/sbin/launch_daemonsu.sh",
-        ] {
-            if self.exists(file) {
-                return UNSUPPORTED_CPIO;
-            }
-        }
-        for file in [
-            ".backup/.magisk",
-            "init.magisk.rc",
-            "overlay/init.magisk.rc",
-        ] {
-            if self.exists(file) {
-                return MAGISK_PATCHED;
-            }
+// Entries have to be of any of the types, if there are any
+fn type_matches(types: &[CpioType], entry: &CpioEntry) -> bool {
+    types.is_empty() || entry.file_type().is_some_and(|t| types.contains(&t))
+}
+
+struct DumpOptions {
+    format: CpioFormat,
+    mtime: u64,
+    inode: InodeMode,
+    level: Option<u32>,
+    trailer: bool,
+    progress: bool,
+    // Normalized directory all entries are written under
+    prefix: Option<String>,
+    // Keep the existing file as path + suffix before overwriting it
+    backup_suffix: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CpioFormat {
+    Newc,
+    NewcCrc,
+    Odc,
+    Bin,
+}
+
+impl CpioFormat {
+    // Names and data are padded to multiples of this
+    fn align(&self) -> usize {
+        match self {
+            CpioFormat::Newc | CpioFormat::NewcCrc => 4,
+            CpioFormat::Odc => 1,
+            CpioFormat::Bin => 2,
         }
-        0
     }
 
-    fn restore(&mut self) -> LoggedResult<()> {
-        let mut backups = HashMap::<String, Box<CpioEntry>>::new();
-        let mut rm_list = String::new();
-        self.entries
-            .extract_if(|name, _| name.starts_with(".backup/"))
-            .for_each(|(name, mut entry)| {
-                if name == ".backup/.rmlist" {
-                    if let Ok(data) = str::from_utf8(&entry.data) {
-                        rm_list.push_str(data);
-                    }
-                } else if name != ".backup/.magisk" {
-                    let new_name = if name.ends_with(".xz") && entry.decompress() {
-                        &name[8..name.len() - 3]
-                    } else {
-                        &name[8..]
-                    };
-                    eprintln!("Restore [{}] -> [{}]", name, new_name);
-                    backups.insert(new_name.to_string(), entry);
-                }
-            });
-        self.rm(".backup", false);
-        if rm_list.is_empty() && backups.is_empty() {
-            self.entries.clear();
-            return Ok(());
-        }
-        for rm in rm_list.split('\0') {
-            if !rm.is_empty() {
-                self.rm(rm, false);
-            }
+    // Inode numbers have to be below this to fit in the header
+    fn ino_limit(&self) -> u64 {
+        match self {
+            CpioFormat::Newc | CpioFormat::NewcCrc => 1 << 32,
+            CpioFormat::Odc => 8u64.pow(6),
+            CpioFormat::Bin => 1 << 16,
         }
-        self.entries.extend(backups);
-
-        Ok(())
     }
+}
 
-    fn backup(&mut self, origin: &mut String, skip_compress: bool) -> LoggedResult<()> {
-        let mut backups = HashMap::<String, Box<CpioEntry>>::new();
-        let mut rm_list = String::new();
-        backups.insert(
-            ".backup".to_string(),
-            Box::new(CpioEntry {
-                mode: S_IFDIR,
-                uid: 0,
-                gid: 0,
-                rdevmajor: 0,
-                rdevminor: 0,
-                data: vec![],
-            }),
-        );
-        let origin = Utf8CStr::from_string(origin);
-        let mut o = Cpio::load_from_file(origin)?;
-        o.rm(".backup", true);
-        self.rm(".backup", true);
+impl Display for CpioFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CpioFormat::Newc => f.write_str("newc"),
+            CpioFormat::NewcCrc => f.write_str("newc-crc"),
+            CpioFormat::Odc => f.write_str("odc"),
+            CpioFormat::Bin => f.write_str("bin"),
+        }
+    }
+}
 
-        let mut lhs = o.entries.into_iter().peekable();
-        let mut rhs = self.entries.iter().peekable();
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CpioCompression {
+    Xz,
+    Lzma,
+}
 
-        loop {
-            enum Action<'a> {
-                Backup(String, Box<CpioEntry>),
-                Record(&'a String),
-                Noop,
-            }
-            let action = match (lhs.peek(), rhs.peek()) {
-                (Some((l, _)), Some((r, re))) => match l.as_str().cmp(r.as_str()) {
-                    Ordering::Less => {
-                        let (l, le) = lhs.next().unwrap();
-                        Action::Backup(l, le)
-                    }
-                    Ordering::Greater => Action::Record(rhs.next().unwrap().0),
-                    Ordering::Equal => {
-                        let (l, le) = lhs.next().unwrap();
-                        let action = if re.data != le.data {
-                            Action::Backup(l, le)
-                        } else {
-                            Action::Noop
-                        };
-                        rhs.next();
-                        action
-                    }
-                },
-                (Some(_), None) => {
-                    let (l, le) = lhs.next().unwrap();
-                    Action::Backup(l, le)
-                }
-                (None, Some(_)) => Action::Record(rhs.next().unwrap().0),
-                (None, None) => {
-                    break;
-                }
-            };
-            match action {
-                Action::Backup(name, mut entry) => {
-                    let backup = if !skip_compress && entry.compress() {
-                        format!(".backup/{}.xz", name)
-                    } else {
-                        format!(".backup/{}", name)
-                    };
-                    eprintln!("Backup [{}] -> [{}]", name, backup);
-                    backups.insert(backup, entry);
-                }
-                Action::Record(name) => {
-                    eprintln!("Record new entry: [{}] -> [.backup/.rmlist]", name);
-                    rm_list.push_str(&format!("{}\0", name));
-                }
-                Action::Noop => {}
-            }
+impl CpioCompression {
+    // Raw LZMA cannot be reliably detected, allow forcing it for incpio with CPIO_COMP
+    fn forced() -> Option<Self> {
+        match env::var("CPIO_COMP").as_deref() {
+            Ok("xz") => Some(CpioCompression::Xz),
+            Ok("lzma") => Some(CpioCompression::Lzma),
+            _ => None,
         }
-        if !rm_list.is_empty() {
-            backups.insert(
-                ".backup/.rmlist".to_string(),
-                Box::new(CpioEntry {
-                    mode: S_IFREG,
-                    uid: 0,
-                    gid: 0,
-                    rdevmajor: 0,
-                    rdevminor: 0,
-                    data: rm_list.as_bytes().to_vec(),
-                }),
-            );
+    }
+
+    fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\xfd7zXZ\x00") {
+            Some(CpioCompression::Xz)
+        } else {
+            None
         }
-        self.entries.extend(backups);
+    }
 
-        Ok(())
+    fn levels(&self) -> RangeInclusive<u32> {
+        match self {
+            CpioCompression::Xz | CpioCompression::Lzma => 0..=9,
+        }
     }
-}
 
-impl CpioEntry {
-    pub(crate) fn compress(&mut self) -> bool {
-        if self.mode & S_IFMT != S_IFREG {
-            return false;
+    fn default_level(&self) -> u32 {
+        match self {
+            CpioCompression::Xz | CpioCompression::Lzma => 6,
         }
-        let mut compressed = Vec::new();
-        if !xz(&self.data, &mut compressed) {
-            eprintln!("xz compression failed");
-            return false;
+    }
+
+    fn compress(&self, buf: &[u8], level: u32, out: &mut Vec<u8>) -> bool {
+        match self {
+            CpioCompression::Xz => xz(buf, level, out),
+            CpioCompression::Lzma => lzma(buf, level, out),
         }
-        self.data = compressed;
-        true
     }
 
-    pub(crate) fn decompress(&mut self) -> bool {
-        if self.mode & S_IFMT != S_IFREG {
-            return false;
+    fn decompress(&self, buf: &[u8], out: &mut Vec<u8>) -> bool {
+        match self {
+            CpioCompression::Xz => unxz(buf, out),
+            CpioCompression::Lzma => unlzma(buf, out),
         }
-        let mut decompressed = Vec::new();
-        if !unxz(&self.data, &mut decompressed) {
-            eprintln!("xz decompression failed");
-            return false;
+    }
+
+    fn decompress_to(&self, buf: &[u8], fd: RawFd) -> bool {
+        match self {
+            CpioCompression::Xz => unxz_fd(buf, fd),
+            CpioCompression::Lzma => unlzma_fd(buf, fd),
         }
-        self.data = decompressed;
-        true
     }
 }
 
-impl Display for CpioEntry {
+impl Display for CpioCompression {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}{}{}{}{}{}{}{}{}{}\t{}\t{}\t{}\t{}:{}",
-            match self.mode & S_IFMT {
-                S_IFDIR => "d",
-                S_IFREG => "-",
-                S_IFLNK => "l",
-                S_IFBLK => "b",
-                S_IFCHR => "c",
-                _ => "?",
-            },
-            if self.mode & S_IRUSR != 0 { "r" } else { "-" },
-            if self.mode & S_IWUSR != 0 { "w" } else { "-" },
-            if self.mode & S_IXUSR != 0 { "x" } else { "-" },
-            if self.mode & S_IRGRP != 0 { "r" } else { "-" },
-            if self.mode & S_IWGRP != 0 { "w" } else { "-" },
-            if self.mode & S_IXGRP != 0 { "x" } else { "-" },
-            if self.mode & S_IROTH != 0 { "r" } else { "-" },
-            if self.mode & S_IWOTH != 0 { "w" } else { "-" },
-            if self.mode & S_IXOTH != 0 { "x" } else { "-" },
-            self.uid,
-            self.gid,
-            Size::from_bytes(self.data.len())
-                .format()
-                .with_style(Style::Abbreviated)
-                .with_base(Base::Base10)
-                .to_string(),
-            self.rdevmajor,
-            self.rdevminor,
-        )
+        match self {
+            CpioCompression::Xz => f.write_str("xz"),
+            CpioCompression::Lzma => f.write_str("lzma"),
+        }
     }
 }
 
-pub fn cpio_commands(argc: i32, argv: *const *const c_char) -> bool {
-    fn inner(argc: i32, argv: *const *const c_char) -> LoggedResult<()> {
-        if argc < 1 {
-            return Err(log_err!("No arguments"));
-        }
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum CpioType {
+    Reg,
+    Dir,
+    Symlink,
+    Block,
+    Char,
+}
 
-        let cmds = map_args(argc, argv)?;
+impl CpioType {
+    pub fn from_mode(mode: mode_t) -> Option<Self> {
+        match mode & S_IFMT {
+            S_IFREG => Some(CpioType::Reg),
+            S_IFDIR => Some(CpioType::Dir),
+            S_IFLNK => Some(CpioType::Symlink),
+            S_IFBLK => Some(CpioType::Block),
+            S_IFCHR => Some(CpioType::Char),
+            _ => None,
+        }
+    }
+}
 
-        let mut cli =
-            CpioCli::from_args(&["magiskboot", "cpio"], &cmds).on_early_exit(print_cpio_usage);
+impl From<CpioType> for mode_t {
+    fn from(t: CpioType) -> Self {
+        match t {
+            CpioType::Reg => S_IFREG,
+            CpioType::Dir => S_IFDIR,
+            CpioType::Symlink => S_IFLNK,
+            CpioType::Block => S_IFBLK,
+            CpioType::Char => S_IFCHR,
+        }
+    }
+}
 
-        let file = Utf8CStr::from_string(&mut cli.file);
-        let mut cpio = if FsPath::from(file).exists() {
-            Cpio::load_from_file(file)?
-        } else {
-            Cpio::new()
-        };
+impl TryFrom<&str> for CpioType {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "f" | "file" | "reg" | "regular" => Ok(CpioType::Reg),
+            "d" | "dir" | "directory" => Ok(CpioType::Dir),
+            "l" | "link" | "symlink" => Ok(CpioType::Symlink),
+            "b" | "block" => Ok(CpioType::Block),
+            "c" | "char" => Ok(CpioType::Char),
+            _ => Err(format!("unknown file type {}", s)),
+        }
+    }
+}
+
+impl Display for CpioType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CpioType::Reg => f.write_str("-"),
+            CpioType::Dir => f.write_str("d"),
+            CpioType::Symlink => f.write_str("l"),
+            CpioType::Block => f.write_str("b"),
+            CpioType::Char => f.write_str("c"),
+        }
+    }
+}
+
+// Cloning copies the data of every entry
+#[derive(Clone)]
+pub struct Cpio {
+    pub(crate) entries: BTreeMap<String, Box<CpioEntry>>,
+    format: Option<CpioFormat>,
+    compression: Option<CpioCompression>,
+    size: usize,
+    file_size: usize,
+    garbage: usize,
+    // Original bytes of entry names that are not valid UTF-8, keyed by their lossy name
+    raw_names: HashMap<String, Vec<u8>>,
+    // Also treat '\' as a separator in the entry paths given to methods
+    backslash: bool,
+}
+
+// Archive metadata is not part of the content
+impl PartialEq for Cpio {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl Eq for Cpio {}
+
+impl Default for Cpio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Cloning copies the entry data
+#[derive(Clone, PartialEq, Eq)]
+pub struct CpioEntry {
+    pub(crate) ino: u32,
+    pub(crate) mode: mode_t,
+    pub(crate) uid: uid_t,
+    pub(crate) gid: gid_t,
+    pub(crate) rdevmajor: dev_t,
+    pub(crate) rdevminor: dev_t,
+    pub(crate) data: DataSource,
+    // Hardlink group of an entry loaded with nlink > 1, 0 if it is not hardlinked
+    pub(crate) link: u32,
+}
+
+// The data of an entry. Files added with add or pack are only read once something needs
+// their contents, so a batch of large files is never held in memory all at once; when
+// dumping right away, only one of them is in memory at a time.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) enum DataSource {
+    Owned(Vec<u8>),
+    // A regular file, with its size when added and the most bytes it may have when read
+    FilePath {
+        path: String,
+        size: u64,
+        max_size: u64,
+    },
+}
+
+impl DataSource {
+    // The size of a file that is not read yet is the one it had when it was added
+    fn len(&self) -> usize {
+        match self {
+            DataSource::Owned(data) => data.len(),
+            DataSource::FilePath { size, .. } => *size as usize,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for DataSource {
+    fn default() -> Self {
+        DataSource::Owned(Vec::new())
+    }
+}
+
+impl From<Vec<u8>> for DataSource {
+    fn from(data: Vec<u8>) -> Self {
+        DataSource::Owned(data)
+    }
+}
+
+// Files are read by Cpio::load_files before any command looks at the data, and by dump_to
+// while writing them, so only owned data is ever accessed this way
+impl Deref for DataSource {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        match self {
+            DataSource::Owned(data) => data,
+            DataSource::FilePath { path, .. } => panic!("data of [{}] is not loaded", path),
+        }
+    }
+}
+
+impl DerefMut for DataSource {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        match self {
+            DataSource::Owned(data) => data,
+            DataSource::FilePath { path, .. } => panic!("data of [{}] is not loaded", path),
+        }
+    }
+}
+
+impl Cpio {
+    // An empty archive, to be filled with entries programmatically
+    pub fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            format: None,
+            compression: None,
+            size: 0,
+            file_size: 0,
+            garbage: 0,
+            raw_names: HashMap::new(),
+            backslash: false,
+        }
+    }
+
+    // The format of the loaded archive, None if the archive is created from scratch
+    pub fn format(&self) -> Option<CpioFormat> {
+        self.format
+    }
+
+    // The compression method of the loaded archive, None if not compressed
+    pub fn compression(&self) -> Option<CpioCompression> {
+        self.compression
+    }
+
+    // The size of the loaded archive in bytes, after decompression
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // The size of the loaded archive in bytes as stored, before decompression
+    pub fn file_size(&self) -> usize {
+        self.file_size
+    }
+
+    // The number of non-zero bytes found after trailers, which should only be zero padding
+    pub fn garbage(&self) -> usize {
+        self.garbage
+    }
+
+    // Names of entries stored as bytes that are not valid UTF-8, with U+FFFD substituted
+    pub fn lossy_names(&self) -> impl Iterator<Item = &str> {
+        self.raw_names.keys().map(String::as_str)
+    }
+
+    // Normalize an entry path given by the caller, as opposed to names from the archive
+    fn norm(&self, path: &str) -> String {
+        norm_path_sep(path, self.backslash)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &CpioEntry)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_ref()))
+    }
+
+    pub fn get(&self, path: &str) -> Option<&CpioEntry> {
+        self.entries.get(&self.norm(path)).map(|e| e.as_ref())
+    }
+
+    pub fn get_mut(&mut self, path: &str) -> Option<&mut CpioEntry> {
+        let path = self.norm(path);
+        self.entries.get_mut(&path).map(|e| e.as_mut())
+    }
+
+    // Returns the entry at path, inserting an empty one with mode 0 for the caller to fill in
+    pub(crate) fn get_or_insert(&mut self, path: &str) -> &mut CpioEntry {
+        let path = self.norm(path);
+        self.entries.entry(path).or_insert_with(|| {
+            Box::new(CpioEntry {
+                ino: 0,
+                mode: 0,
+                uid: 0,
+                gid: 0,
+                rdevmajor: 0,
+                rdevminor: 0,
+                data: DataSource::default(),
+                link: 0,
+            })
+        })
+    }
+
+    // Returns the directory at path, creating it if it does not exist
+    pub fn get_or_insert_dir(&mut self, path: &str) -> CpioResult<&mut CpioEntry> {
+        let path = self.norm(path);
+        let entry = self.get_or_insert(&path);
+        match entry.mode & S_IFMT {
+            0 => entry.mode = S_IFDIR | 0o755,
+            S_IFDIR => {}
+            _ => return Err(CpioError::InvalidEntry(path, "not a directory")),
+        }
+        Ok(entry)
+    }
+
+    fn load_from_data(data: &[u8], opts: &LoadOptions) -> CpioResult<Self> {
+        Self::load_from_input(&mut SliceInput::new(data), opts)
+    }
 
-        for cmd in cli.commands {
-            if cmd.starts_with('#') {
+    fn load_from_input<'a>(input: &mut impl LoadInput<'a>, opts: &LoadOptions) -> CpioResult<Self> {
+        let mut cpio = Cpio::new();
+        // First pass: sequentially locate all headers, names, and data
+        let mut records = Vec::new();
+        let mut progress = Progress::new(opts.progress, "Loading", None);
+        let limits = &opts.limits;
+        let mut total_data = 0_usize;
+        // Inode numbers restart in each concatenated archive, so hardlinks are only found
+        // within one. Groups are numbered from 1 in the order they are found.
+        let mut segment = 0_usize;
+        let mut groups = HashMap::new();
+        while !input.peek(1)?.is_empty() {
+            let (hdr, hdr_sz) = match RawHeader::parse(input.peek(size_of::<CpioHeader>())?) {
+                Ok(hdr) => hdr,
+                Err(CpioError::BadFormat(_)) if opts.lenient => {
+                    // Resynchronize at the next ASCII header, like after a trailer
+                    let pos = input.offset();
+                    let skip = input.find(1, usize::MAX, b"07070")?;
+                    input.skip(skip.unwrap_or(usize::MAX))?;
+                    let skip = input.offset() - pos;
+                    eprintln!("Skipped {} bytes of damaged data at offset {}", skip, pos);
+                    cpio.garbage += skip;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let align = hdr.format.align();
+            cpio.format.get_or_insert(hdr.format);
+            input.skip(hdr_sz)?;
+            let name = input.take(hdr.name_sz)?;
+            input.align(align)?;
+            if name.as_ref() == b"TRAILER!!!\0" {
+                let pos = input.offset();
+                let (next, garbage) = next_archive(input, hdr.format, limits.trailer_scan)?;
+                if garbage > 0 {
+                    eprintln!(
+                        "Found {} bytes of unexpected data after trailer at offset {}",
+                        garbage, pos
+                    );
+                    cpio.garbage += garbage;
+                }
+                if !next {
+                    break;
+                }
+                segment += 1;
                 continue;
             }
-            let mut cli = CpioCommand::from_args(
-                &["magiskboot", "cpio", file],
-                cmd.split(' ')
-                    .filter(|x| !x.is_empty())
+            // Check before reading or constructing anything, the second pass can be far
+            // more expensive
+            if records.len() >= limits.max_entries {
+                return Err(CpioError::LimitExceeded("entries", limits.max_entries));
+            }
+            total_data = total_data.saturating_add(hdr.file_sz);
+            if total_data > limits.max_data {
+                return Err(CpioError::LimitExceeded("data bytes", limits.max_data));
+            }
+            let file = input.take(hdr.file_sz)?;
+            input.align(align)?;
+            let mode: mode_t = hdr.mode.as_();
+            let link = if hdr.nlink > 1 && mode & S_IFMT != S_IFDIR {
+                let next = groups.len() as u32 + 1;
+                *groups.entry((segment, hdr.ino)).or_insert(next)
+            } else {
+                0
+            };
+            records.push(EntryRecord {
+                hdr,
+                name,
+                file,
+                link,
+            });
+            progress.tick();
+        }
+        progress.report();
+
+        // Second pass: construct entries, optionally across multiple threads
+        let threads = opts.threads.clamp(1, records.len().max(1));
+        let entries = if threads == 1 {
+            vec![EntryRecord::build_all(&mut records)?]
+        } else {
+            let chunk_sz = records.len().div_ceil(threads);
+            thread::scope(|s| {
+                records
+                    .chunks_mut(chunk_sz)
+                    .map(|chunk| s.spawn(move || EntryRecord::build_all(chunk)))
                     .collect::<Vec<_>>()
-                    .as_slice(),
-            )
-            .on_early_exit(print_cpio_usage);
-
-            match &mut cli.action {
-                CpioAction::Test(_) => exit(cpio.test()),
-                CpioAction::Restore(_) => cpio.restore()?,
-                CpioAction::Patch(_) => cpio.patch(),
-                CpioAction::Exists(Exists { path }) => {
-                    if cpio.exists(path) {
-                        exit(0);
-                    } else {
-                        exit(1);
+                    .into_iter()
+                    .map(|h| h.join().unwrap())
+                    .collect::<CpioResult<Vec<_>>>()
+            })?
+        };
+        // Merge in archive order, so later duplicates replace earlier ones
+        for (name, entry, raw) in entries.into_iter().flatten() {
+            // Store entries with canonical names; "." and ".." normalize to nothing
+            if name.is_empty() {
+                continue;
+            }
+            match raw {
+                Some(raw) => {
+                    if let Some(prev) = cpio.raw_names.insert(name.clone(), raw) {
+                        if prev != cpio.raw_names[&name] {
+                            return Err(CpioError::InvalidEntry(name, "ambiguous lossy name"));
+                        }
                     }
                 }
-                CpioAction::Backup(Backup {
-                    origin,
-                    skip_compress,
-                }) => cpio.backup(origin, *skip_compress)?,
-                CpioAction::Remove(Remove { path, recursive }) => cpio.rm(path, *recursive),
-                CpioAction::Move(Move { from, to }) => cpio.mv(from, to)?,
-                CpioAction::MakeDir(MakeDir { mode, dir }) => cpio.mkdir(*mode, dir),
-                CpioAction::Link(Link { src, dst }) => cpio.ln(src, dst),
-                CpioAction::Add(Add { mode, path, file }) => cpio.add(*mode, path, file)?,
-                CpioAction::Extract(Extract { paths }) => {
-                    if !paths.is_empty() && paths.len() != 2 {
-                        return Err(log_err!("invalid arguments"));
-                    }
-                    let mut it = paths.iter_mut();
-                    cpio.extract(it.next(), it.next())?;
+                None => {
+                    cpio.raw_names.remove(&name);
+                }
+            }
+            cpio.entries.insert(name, entry);
+        }
+        cpio.size = input.offset();
+        cpio.file_size = cpio.size;
+        Ok(cpio)
+    }
+
+    // Load a possibly compressed archive from an untrusted source
+    pub fn load(path: &Utf8CStr, limits: CpioLimits) -> CpioResult<Self> {
+        let opts = LoadOptions {
+            limits,
+            ..Default::default()
+        };
+        Self::load_from_file(path, &opts)
+    }
+
+    // Like load, for an uncompressed archive already in memory
+    pub fn load_bytes(data: &[u8], limits: CpioLimits) -> CpioResult<Self> {
+        let opts = LoadOptions {
+            limits,
+            ..Default::default()
+        };
+        Self::load_from_data(data, &opts)
+    }
+
+    fn load_from_file(path: &Utf8CStr, opts: &LoadOptions) -> CpioResult<Self> {
+        eprintln!("Loading cpio: [{}]", path);
+        let file = MappedFile::open(path).io_context("read", path)?;
+        let data = file.as_ref();
+        let comp = opts.compression.or_else(|| CpioCompression::detect(data));
+        let mut cpio = match comp {
+            Some(comp) => Self::load_compressed(data, comp, opts)?,
+            None => Self::load_from_data(data, opts)?,
+        };
+        cpio.compression = comp;
+        cpio.file_size = data.len();
+        Ok(cpio)
+    }
+
+    // Parse the decoder output as it is produced, so the whole decompressed archive is never
+    // held in memory on top of the entries
+    fn load_compressed(data: &[u8], comp: CpioCompression, opts: &LoadOptions) -> CpioResult<Self> {
+        let mut fds = [0; 2];
+        unsafe { pipe2(fds.as_mut_ptr(), O_CLOEXEC) }.as_os_err()?;
+        let (rd, wr) = unsafe { (File::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        thread::scope(|s| {
+            // The write end is closed once the decoder is done, ending the stream
+            let decoder = s.spawn(move || comp.decompress_to(data, wr.as_raw_fd()));
+            let mut input = StreamInput::new(BufReader::new(rd));
+            let result = Self::load_from_input(&mut input, opts);
+            // The decoder cannot write to a closed pipe, read everything it still produces
+            let finished = input.finish();
+            if !decoder.join().unwrap() {
+                return Err(CpioError::BadFormat("decompression failed"));
+            }
+            finished?;
+            result
+        })
+    }
+
+    // Check the structural invariants of all entries, returning every problem found
+    pub fn validate(&self) -> Result<(), Vec<CpioError>> {
+        let mut errors = Vec::new();
+        for (name, entry) in self.iter() {
+            let mut err = |msg| errors.push(CpioError::InvalidEntry(name.to_string(), msg));
+            if name.is_empty() || norm_path(name) != name {
+                err("path is not canonical");
+            }
+            if entry.mode & !(S_IFMT | 0o7777) != 0 {
+                err("invalid mode bits");
+            }
+            match entry.file_type() {
+                None => err("unknown entry type"),
+                Some(CpioType::Dir | CpioType::Block | CpioType::Char)
+                    if !entry.data.is_empty() =>
+                {
+                    err("unexpected data")
+                }
+                Some(CpioType::Symlink) if entry.data.is_empty() => err("empty symlink"),
+                _ => {}
+            }
+            if u32::try_from(entry.data.len()).is_err() {
+                err("data is too large");
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn dump(&self, path: &Utf8CStr, opts: &DumpOptions) -> CpioResult<()> {
+        // Make sure we never write a structurally invalid archive
+        if let Err(errors) = self.validate() {
+            return Err(errors.into_iter().next().unwrap());
+        }
+        if let Some(prefix) = &opts.prefix {
+            self.check_prefix(prefix)?;
+        }
+        let level = match self.compression {
+            Some(comp) => {
+                let level = opts.level.unwrap_or(comp.default_level());
+                if !comp.levels().contains(&level) {
+                    return Err(CpioError::InvalidLevel(level, comp));
+                }
+                level
+            }
+            None => 0,
+        };
+        // Write everything to a temporary file first, and only replace path once the whole
+        // archive is written, so a failure never leaves a partial archive behind
+        let tmp = path
+            .with_suffix(".tmp")
+            .map_err(|_| CpioError::InvalidPath(path.to_string()))?;
+        eprintln!("Dumping cpio: [{}]", path);
+        note_write();
+        if let Err(e) = self.dump_to_file(&tmp, level, opts) {
+            FsPath::from(&tmp).remove().ok();
+            return Err(e);
+        }
+        let path = FsPath::from(path);
+        if let Some(suffix) = &opts.backup_suffix {
+            let backup = path
+                .with_suffix(suffix)
+                .map_err(|_| CpioError::InvalidPath(suffix.clone()))?;
+            if path.exists() {
+                eprintln!("Backup [{}] -> [{}]", path, backup);
+                path.rename_to(&backup).io_context("rename", path)?;
+            }
+        }
+        FsPath::from(&tmp)
+            .rename_to(path)
+            .io_context("rename", &tmp)?;
+        Ok(())
+    }
+
+    // Entries cannot be written under a prefix they already are within
+    fn check_prefix(&self, prefix: &str) -> CpioResult<()> {
+        if prefix.is_empty() {
+            return Ok(());
+        }
+        match self.entries.keys().find(|k| strip_dir(k, prefix).is_some()) {
+            Some(name) => Err(CpioError::InvalidEntry(
+                name.clone(),
+                "already under the prefix",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn dump_to_file(&self, path: &Utf8CStr, level: u32, opts: &DumpOptions) -> CpioResult<()> {
+        let mut file = BufWriter::new(File::create(path).io_context("create", path)?);
+        match self.compression {
+            None => self.dump_to(&mut file, opts).io_context("write", path)?,
+            Some(comp) => {
+                // Compress with the same method as the input archive
+                let buf = self.dump_to_vec(opts)?;
+                let mut out = Vec::new();
+                if !comp.compress(&buf, level, &mut out) {
+                    return Err(CpioError::Unsupported("compression failed"));
+                }
+                file.write_all(&out).io_context("write", path)?;
+            }
+        }
+        file.flush().io_context("write", path)?;
+        Ok(())
+    }
+
+    // Buffering and flushing are left to the caller
+    fn dump_to<W: Write>(&self, file: &mut W, opts: &DumpOptions) -> CpioResult<()> {
+        let mut pos = 0usize;
+        // Inode fields of the old formats are too narrow for the usual starting number
+        let mut inode = match opts.format {
+            CpioFormat::Newc | CpioFormat::NewcCrc => 300000u64,
+            CpioFormat::Odc | CpioFormat::Bin => 1,
+        };
+        // Hash and path derived numbers wrap around to fit the inode field of the format
+        let limit = opts.format.ino_limit();
+        // Path derived numbers are stable across runs; collisions probe the next number
+        let mut used = HashSet::new();
+        let mut path_ino = |name: &[u8]| {
+            if used.len() as u64 >= limit {
+                let name = String::from_utf8_lossy(name).into_owned();
+                return Err(CpioError::FieldOverflow(name, "ino", opts.format));
+            }
+            let mut ino = u64::from(fnv1a(name)) % limit;
+            while !used.insert(ino) {
+                ino = (ino + 1) % limit;
+            }
+            Ok(ino)
+        };
+        // The original numbers may not fit an old format; if any does not, all are renumbered
+        // in order, so entries that shared a number still do
+        let renumber = matches!(opts.inode, InodeMode::Keep)
+            && self.entries.values().any(|e| u64::from(e.ino) >= limit);
+        let mut renumbered = HashMap::new();
+        // Hardlinks share the number derived from the first path of their group
+        let mut links = HashMap::new();
+        let mut nlinks = HashMap::new();
+        for entry in self.entries.values().filter(|e| e.link != 0) {
+            *nlinks.entry(entry.link).or_insert(0_u32) += 1;
+        }
+        let prefix = opts.prefix.as_deref().unwrap_or_default();
+        if !prefix.is_empty() {
+            // Create all directories leading to the entries, e.g. "a" and "a/b" for "a/b"
+            let dir = CpioEntry {
+                ino: 0,
+                mode: S_IFDIR | 0o755,
+                uid: 0,
+                gid: 0,
+                rdevmajor: 0,
+                rdevminor: 0,
+                data: DataSource::default(),
+                link: 0,
+            };
+            let ends = prefix.match_indices('/').map(|(i, _)| i);
+            for end in ends.chain([prefix.len()]) {
+                let name = prefix[..end].as_bytes();
+                let ino = match opts.inode {
+                    InodeMode::Path => path_ino(name)?,
+                    _ => inode,
+                };
+                write_entry(file, opts, name, &dir, ino, 1, &mut pos)?;
+                inode += 1;
+            }
+        }
+        let mut full_name = Vec::new();
+        let mut progress = Progress::new(opts.progress, "Dumping", Some(self.entries.len()));
+        for (name, entry) in &self.entries {
+            // Files that are not read yet are read one at a time
+            let loaded;
+            let entry = match &entry.data {
+                DataSource::FilePath { path, max_size, .. } => {
+                    loaded = CpioEntry {
+                        data: read_file(path, *max_size)?.into(),
+                        ..CpioEntry::clone(entry)
+                    };
+                    &loaded
                 }
-                CpioAction::List(List { path, recursive }) => {
-                    cpio.ls(path.as_str(), *recursive);
-                    exit(0);
+                DataSource::Owned(_) => entry.as_ref(),
+            };
+            let mut name = self
+                .raw_names
+                .get(name)
+                .map_or(name.as_bytes(), Vec::as_slice);
+            if !prefix.is_empty() {
+                full_name.clear();
+                full_name.extend_from_slice(prefix.as_bytes());
+                full_name.push(b'/');
+                full_name.extend_from_slice(name);
+                name = &full_name;
+            }
+            // Only modes that keep hardlinks on one inode write them as hardlinks
+            let (ino, linked) = match opts.inode {
+                InodeMode::Sequential => (inode, false),
+                InodeMode::Keep if entry.ino != 0 && renumber => {
+                    (*renumbered.entry(entry.ino).or_insert(inode), true)
                 }
+                InodeMode::Keep if entry.ino != 0 => (entry.ino.into(), true),
+                InodeMode::Keep => (inode, false),
+                InodeMode::Hash => (u64::from(fnv1a(&entry.data)) % limit, false),
+                InodeMode::Path if entry.link != 0 => match links.get(&entry.link) {
+                    Some(ino) => (*ino, true),
+                    None => {
+                        let ino = path_ino(name)?;
+                        links.insert(entry.link, ino);
+                        (ino, true)
+                    }
+                },
+                InodeMode::Path => (path_ino(name)?, false),
+            };
+            let nlink = match nlinks.get(&entry.link) {
+                Some(n) if linked => *n,
+                _ => 1,
             };
+            write_entry(file, opts, name, entry, ino, nlink, &mut pos)?;
+            inode += 1;
+            progress.tick();
+        }
+        progress.report();
+        if !opts.trailer {
+            return Ok(());
         }
-        cpio.dump(file)?;
+        let trailer = CpioEntry {
+            ino: 0,
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+            rdevmajor: 0,
+            rdevminor: 0,
+            data: DataSource::default(),
+            link: 0,
+        };
+        write_entry(file, opts, b"TRAILER!!!", &trailer, inode, 1, &mut pos)?;
         Ok(())
     }
-    inner(argc, argv)
-        .log_with_msg(|w| w.write_str("Failed to process cpio"))
-        .is_ok()
-}
 
-fn x8u(x: &[u8; 8]) -> LoggedResult<u32> {
-    // parse hex
-    let mut ret = 0u32;
-    let s = str::from_utf8(x).log_with_msg(|w| w.write_str("bad cpio header"))?;
-    for c in s.chars() {
-        ret = ret * 16 + c.to_digit(16).ok_or_else(|| log_err!("bad cpio header"))?;
+    fn dump_to_vec(&self, opts: &DumpOptions) -> CpioResult<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.dump_to(&mut buf, opts)?;
+        Ok(buf)
     }
-    Ok(ret)
-}
 
-#[inline(always)]
-fn align_4(x: usize) -> usize {
-    (x + 3) & !3
-}
+    fn rm(&mut self, path: &str, recursive: bool) {
+        let path = self.norm(path);
+        if self.entries.remove(&path).is_some() {
+            self.raw_names.remove(&path);
+            eprintln!("Removed entry [{}]", path);
+        }
+        if recursive {
+            let path = path + "/";
+            let raw_names = &mut self.raw_names;
+            self.entries.retain(|k, _| {
+                if k.starts_with(&path) {
+                    raw_names.remove(k);
+                    eprintln!("Removed entry [{}]", k);
+                    false
+                } else {
+                    true
+                }
+            })
+        }
+    }
 
-#[inline(always)]
-fn norm_path(path: &str) -> String {
-    path.split('/')
-        .filter(|x| !x.is_empty())
-        .intersperse("/")
-        .collect()
-}
+    // The entries that rm would remove with the same arguments
+    fn rm_matches(&self, path: &str, recursive: bool) -> Vec<&str> {
+        let path = self.norm(path);
+        let prefix = path.clone() + "/";
+        self.entries
+            .keys()
+            .filter(|k| **k == path || (recursive && k.starts_with(&prefix)))
+            .map(String::as_str)
+            .collect()
+    }
 
-fn parse_mode(s: &str) -> Result<mode_t, String> {
-    mode_t::from_str_radix(s, 8).map_err(|e| e.to_string())
+    fn prune_empty(&mut self, path: &str) {
+        let path = self.norm(path);
+        let prefix = if path.is_empty() {
+            path.clone()
+        } else {
+            path.clone() + "/"
+        };
+        // Never prune directories that symlinks resolve to
+        let targets: HashSet<String> = self
+            .iter()
+            .filter(|(_, e)| e.mode & S_IFMT == S_IFLNK)
+            .map(|(name, e)| {
+                let target = String::from_utf8_lossy(&e.data);
+                match name.rsplit_once('/') {
+                    Some((parent, _)) if !target.starts_with('/') => {
+                        norm_path(&format!("{}/{}", parent, target))
+                    }
+                    _ => norm_path(&target),
+                }
+            })
+            .collect();
+        // Removing a directory may leave its parent empty, repeat until nothing changes
+        loop {
+            let empty: Vec<String> = self
+                .iter()
+                .filter(|(name, e)| {
+                    e.mode & S_IFMT == S_IFDIR
+                        && (**name == path || name.starts_with(&prefix))
+                        && !targets.contains(*name)
+                })
+                .filter(|(name, _)| {
+                    let children = format!("{}/", name);
+                    !self
+                        .entries
+                        .range(children.clone()..)
+                        .next()
+                        .is_some_and(|(k, _)| k.starts_with(&children))
+                })
+                .map(|(name, _)| name.to_string())
+                .collect();
+            if empty.is_empty() {
+                break;
+            }
+            for name in empty {
+                self.entries.remove(&name);
+                self.raw_names.remove(&name);
+                eprintln!("Pruned empty directory [{}]", name);
+            }
+        }
+    }
+
+    fn extract_entry(&self, path: &str, out: &mut String, opts: &ExtractOptions) -> CpioResult<()> {
+        let entry = self
+            .entries
+            .get(path)
+            .ok_or_else(|| CpioError::NotFound(path.to_string()))?;
+        if opts.list {
+            println!("{}\t{}", entry, out);
+            return Ok(());
+        }
+        eprintln!("Extracting entry [{}] to [{}]", path, out);
+
+        let out = Utf8CStr::from_string(out);
+        let out = FsPath::from(out);
+
+        let mut buf = Utf8CStrBufArr::default();
+
+        // Make sure its parent directories exist
+        if out.parent(&mut buf) {
+            FsPath::from(&buf)
+                .mkdirs(opts.dir_mode)
+                .io_context("create directory", &buf)?;
+        }
+
+        let mode: mode_t = (entry.mode & 0o777).into();
+
+        note_write();
+        match entry.file_type() {
+            Some(CpioType::Dir) => out.mkdir(mode).io_context("create directory", out)?,
+            Some(CpioType::Reg) => {
+                let mut file = out
+                    .create(O_CREAT | O_TRUNC | O_WRONLY | O_CLOEXEC, mode)
+                    .io_context("create", out)?;
+                if opts.sparse {
+                    write_sparse(&mut file, &entry.data).io_context("write", out)?;
+                } else {
+                    file.write_all(&entry.data).io_context("write", out)?;
+                }
+            }
+            Some(CpioType::Symlink) => {
+                buf.clear();
+                buf.push_str(
+                    str::from_utf8(entry.data.as_slice())
+                        .map_err(|_| CpioError::BadFormat("invalid symlink target"))?,
+                );
+                FsPath::from(&buf)
+                    .symlink_to(out)
+                    .io_context("create symlink", out)?;
+            }
+            Some(CpioType::Block | CpioType::Char) => {
+                let bad_dev = |_| CpioError::BadFormat("invalid device number");
+                let dev = makedev(
+                    entry.rdevmajor.try_into().map_err(bad_dev)?,
+                    entry.rdevminor.try_into().map_err(bad_dev)?,
+                );
+                unsafe { mknod(out.as_ptr().cast(), entry.mode, dev) };
+            }
+            None => {
+                return Err(CpioError::Unsupported("unknown entry type"));
+            }
+        }
+        if opts.verify && !verify_extracted(entry, out)? {
+            return Err(CpioError::Mismatch(out.to_string()));
+        }
+        Ok(())
+    }
+
+    // Symlinks are created last, so that no entry is written through a symlink that was
+    // itself extracted from the archive
+    fn unpack(&self, dir: &str, opts: &ExtractOptions) -> CpioResult<()> {
+        let (links, others): (Vec<_>, Vec<_>) = self
+            .iter()
+            .partition(|(_, e)| e.file_type() == Some(CpioType::Symlink));
+        let mut progress = Progress::new(opts.progress, "Extracting", Some(self.entries.len()));
+        for (path, _) in others.into_iter().chain(links) {
+            self.extract_entry(path, &mut format!("{}/{}", dir, path), opts)?;
+            progress.tick();
+        }
+        progress.report();
+        Ok(())
+    }
+
+    fn extract_flat(
+        &self,
+        dir: &str,
+        opts: &ExtractOptions,
+        filter: &EntryFilter,
+    ) -> CpioResult<()> {
+        let mut seen = HashSet::new();
+        let selected = |(path, entry): &(&str, &CpioEntry)| {
+            entry.file_type() == Some(CpioType::Reg) && filter.matches(path, entry)
+        };
+        let total = self.iter().filter(selected).count();
+        let mut progress = Progress::new(opts.progress, "Extracting", Some(total));
+        for (path, _) in self.iter().filter(selected) {
+            let name = path.rsplit_once('/').map_or(path, |(_, name)| name);
+            let mut out = format!("{}/{}", dir, name);
+            if !seen.insert(out.clone()) {
+                let mut n = 1;
+                while !seen.insert(format!("{}.{}", out, n)) {
+                    n += 1;
+                }
+                let renamed = format!("{}.{}", out, n);
+                eprintln!("Duplicated name [{}], writing to [{}]", out, renamed);
+                out = renamed;
+            }
+            self.extract_entry(path, &mut out, opts)?;
+            progress.tick();
+        }
+        progress.report();
+        Ok(())
+    }
+
+    // Build an archive from the directory tree at root, keeping the real type, mode,
+    // and owner of each file. Symlinks are stored as is and never followed.
+    pub fn from_directory(root: &Utf8CStr) -> CpioResult<Cpio> {
+        let mut cpio = Cpio::new();
+        cpio.add_tree(root, "")?;
+        cpio.load_files()?;
+        Ok(cpio)
+    }
+
+    // Read the files of all entries added without reading them yet
+    fn load_files(&mut self) -> CpioResult<()> {
+        for entry in self.entries.values_mut() {
+            if let DataSource::FilePath { path, max_size, .. } = &entry.data {
+                entry.data = read_file(path, *max_size)?.into();
+            }
+        }
+        Ok(())
+    }
+
+    fn add_tree(&mut self, dir: &Utf8CStr, prefix: &str) -> CpioResult<()> {
+        let mut d = Directory::open(dir).io_context("open directory", dir)?;
+        while let Some(e) = d.read().io_context("read directory", dir)? {
+            let file_name = e.d_name().to_str().map_err(|_| {
+                CpioError::InvalidPath(format!("{}/{}", dir, e.d_name().to_string_lossy()))
+            })?;
+            let name = format!("{}{}", prefix, file_name);
+            let mut path = format!("{}/{}", dir, file_name);
+            let file = FsPath::from(Utf8CStr::from_string(&mut path));
+            let attr = file.get_attr().io_context("stat", file)?;
+
+            let mut entry = Box::new(CpioEntry {
+                ino: 0,
+                mode: attr.st.st_mode.as_(),
+                uid: attr.st.st_uid,
+                gid: attr.st.st_gid,
+                rdevmajor: 0,
+                rdevminor: 0,
+                data: DataSource::default(),
+                link: 0,
+            });
+            if attr.is_dir() {
+                self.entries.insert(name.clone(), entry);
+                self.add_tree(file, &format!("{}/", name))?;
+                continue;
+            } else if attr.is_file() {
+                entry.data = DataSource::FilePath {
+                    path: file.to_string(),
+                    size: attr.st.st_size.as_(),
+                    max_size: u64::MAX,
+                };
+            } else if attr.is_symlink() {
+                let mut buf = Utf8CStrBufArr::default();
+                file.read_link(&mut buf).io_context("read link", file)?;
+                entry.data.extend_from_slice(buf.as_bytes());
+            } else if attr.is_block_device() || attr.is_char_device() {
+                entry.rdevmajor = unsafe { major(attr.st.st_rdev.as_()) }.as_();
+                entry.rdevminor = unsafe { minor(attr.st.st_rdev.as_()) }.as_();
+            } else {
+                return Err(CpioError::Unsupported("unsupported file type"));
+            }
+            self.entries.insert(name, entry);
+        }
+        Ok(())
+    }
+
+    // Returns the regular file at path and the requested range of its data, clamped to the
+    // data unless strict
+    fn entry_range(
+        &self,
+        path: &str,
+        offset: u64,
+        bytes: Option<u64>,
+        strict: bool,
+    ) -> CpioResult<(&CpioEntry, &[u8])> {
+        let entry = self
+            .get(path)
+            .ok_or_else(|| CpioError::NotFound(path.to_string()))?;
+        if entry.mode & S_IFMT != S_IFREG {
+            return Err(CpioError::Unsupported("not a regular file"));
+        }
+        let len = entry.data.len();
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        let end = bytes.map_or(len, |n| {
+            start.saturating_add(usize::try_from(n).unwrap_or(usize::MAX))
+        });
+        if strict && (start > len || end > len) {
+            return Err(CpioError::InvalidEntry(
+                path.to_string(),
+                "range is out of bounds",
+            ));
+        }
+        Ok((entry, &entry.data[start.min(len)..end.min(len)]))
+    }
+
+    fn export(&self, args: &mut Export) -> CpioResult<()> {
+        let path = self.norm(&args.path);
+        let (entry, data) = self.entry_range(&path, args.offset, args.bytes, args.strict)?;
+        eprintln!("Exporting entry [{}] to [{}]", path, args.out);
+        let out = Utf8CStr::from_string(&mut args.out);
+        note_write();
+        let mut file = FsPath::from(out)
+            .create(
+                O_CREAT | O_TRUNC | O_WRONLY | O_CLOEXEC,
+                (entry.mode & 0o777).into(),
+            )
+            .io_context("create", out)?;
+        file.write_all(data).io_context("write", out)?;
+        Ok(())
+    }
+
+    fn dump_hex(&self, args: &DumpHex) -> CpioResult<()> {
+        let path = self.norm(&args.path);
+        let (_, data) = self.entry_range(&path, args.offset, args.bytes, args.strict)?;
+        let mut out = BufWriter::new(io::stdout().lock());
+        // Same layout as hexdump -C, with offsets relative to the start of the entry
+        for (i, line) in data.chunks(16).enumerate() {
+            write!(out, "{:08x} ", args.offset as usize + i * 16)?;
+            for j in 0..16 {
+                if j == 8 {
+                    write!(out, " ")?;
+                }
+                match line.get(j) {
+                    Some(b) => write!(out, " {:02x}", b)?,
+                    None => write!(out, "   ")?,
+                }
+            }
+            write!(out, "  |")?;
+            for &b in line {
+                let c = if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                };
+                write!(out, "{}", c)?;
+            }
+            writeln!(out, "|")?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    fn extract(
+        &self,
+        path: Option<&mut String>,
+        out: Option<&mut String>,
+        opts: &ExtractOptions,
+        filter: &EntryFilter,
+    ) -> CpioResult<()> {
+        let path = path.map(|s| self.norm(s));
+        if let (Some(path), Some(out)) = (&path, out) {
+            return self.extract_entry(path, out, opts);
+        } else {
+            let selected = |(path, entry): &(&str, &CpioEntry)| filter.matches(path, entry);
+            let total = self.iter().filter(selected).count();
+            let mut progress = Progress::new(opts.progress, "Extracting", Some(total));
+            for (path, _) in self.iter().filter(selected) {
+                self.extract_entry(path, &mut path.to_string(), opts)?;
+                progress.tick();
+            }
+            progress.report();
+        }
+        Ok(())
+    }
+
+    // JSON: {"format": string|null, "compression": string|null, "size": number,
+    //        "file_size": number, "entries": number, "garbage": number}
+    // format and compression are the names accepted by --out-format and CPIO_COMP
+    fn info_json(&self) -> Json {
+        Json::Object(vec![
+            ("format", self.format().map(|f| f.to_string()).into()),
+            (
+                "compression",
+                self.compression().map(|c| c.to_string()).into(),
+            ),
+            ("size", self.size().into()),
+            ("file_size", self.file_size().into()),
+            ("entries", self.entries.len().into()),
+            ("garbage", self.garbage().into()),
+        ])
+    }
+
+    fn info(&self, json: bool) {
+        if json {
+            println!("{}", self.info_json());
+            return;
+        }
+        match self.format() {
+            Some(format) => println!("format\t{}", format),
+            None => println!("format\tnone"),
+        }
+        match self.compression() {
+            Some(comp) => println!("compression\t{}", comp),
+            None => println!("compression\tnone"),
+        }
+        println!("size\t{}", self.size());
+        println!("file_size\t{}", self.file_size());
+        println!("entries\t{}", self.entries.len());
+        println!("garbage\t{}", self.garbage());
+    }
+
+    fn stat(&self, path: &str, json: bool) -> CpioResult<()> {
+        let path = self.norm(path);
+        let entry = self
+            .entries
+            .get(&path)
+            .ok_or_else(|| CpioError::NotFound(path.clone()))?;
+        if json {
+            println!("{}", entry.to_json(&path));
+            return Ok(());
+        }
+        println!("name\t{}", path);
+        match entry.file_type() {
+            Some(t) => println!("type\t{}", t),
+            None => println!("type\tunknown"),
+        }
+        println!("mode\t{:04o}", entry.mode & 0o7777);
+        println!("uid\t{}", entry.uid);
+        println!("gid\t{}", entry.gid);
+        println!("rdev\t{},{}", entry.rdevmajor, entry.rdevminor);
+        println!("size\t{}", entry.data.len());
+        println!("ino\t{}", entry.ino);
+        if entry.mode & S_IFMT == S_IFLNK {
+            println!("target\t{}", String::from_utf8_lossy(&entry.data));
+        }
+        Ok(())
+    }
+
+    fn verify_tree(&self) -> bool {
+        let is_dir = |path: &str| {
+            self.entries
+                .get(path)
+                .is_some_and(|e| e.mode & S_IFMT == S_IFDIR)
+        };
+        let mut ok = true;
+        for (name, entry) in self.iter() {
+            let parent = name.rsplit_once('/').map(|(p, _)| p);
+            if let Some(parent) = parent {
+                if !is_dir(parent) {
+                    println!("Missing parent directory [{}] of [{}]", parent, name);
+                    ok = false;
+                }
+            }
+            if entry.mode & S_IFMT != S_IFLNK {
+                continue;
+            }
+            let target = String::from_utf8_lossy(&entry.data);
+            if target.starts_with('/') {
+                // Absolute targets point outside of the ramdisk
+                println!("Absolute symlink [{}] -> [{}]", name, target);
+                continue;
+            }
+            let resolved = match parent {
+                Some(parent) => norm_path(&format!("{}/{}", parent, target)),
+                None => norm_path(&target),
+            };
+            // An empty path refers to the root of the ramdisk
+            if !resolved.is_empty() && !self.entries.contains_key(&resolved) {
+                println!("Dangling symlink [{}] -> [{}]", name, target);
+                ok = false;
+            }
+        }
+        ok
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.get(path).is_some()
+    }
+
+    // Unlike exists, this has to scan through all entries
+    fn exists_ignore_case(&self, path: &str) -> bool {
+        let path = self.norm(path);
+        self.entries.keys().any(|k| k.eq_ignore_ascii_case(&path))
+    }
+
+    // Fails if a parent of path is not a directory, so that no entry ends up under a file
+    fn check_parents(&mut self, path: &str, create: bool) -> CpioResult<()> {
+        let path = self.norm(path);
+        let mut end = 0;
+        while let Some(i) = path[end..].find('/') {
+            end += i;
+            let parent = &path[..end];
+            match self.entries.get(parent) {
+                Some(e) if e.mode & S_IFMT != S_IFDIR => {
+                    return Err(CpioError::InvalidEntry(
+                        parent.to_string(),
+                        "not a directory",
+                    ));
+                }
+                Some(_) => {}
+                None if create => {
+                    self.get_or_insert_dir(parent)?;
+                    eprintln!("Create directory [{}] (0755)", parent);
+                }
+                None => {}
+            }
+            end += 1;
+        }
+        Ok(())
+    }
+
+    fn add(
+        &mut self,
+        mode: AddMode,
+        path: &str,
+        file: &mut String,
+        max_size: Option<u64>,
+        parents: bool,
+    ) -> CpioResult<()> {
+        if path.ends_with('/') || (self.backslash && path.ends_with('\\')) {
+            return Err(CpioError::InvalidPath(path.to_string()));
+        }
+        self.check_parents(path, false)?;
+        let file = Utf8CStr::from_string(file);
+        let file = FsPath::from(&file);
+        let attr = file.get_attr().io_context("stat", file)?;
+        let mode = match mode {
+            AddMode::Fixed(mode) => mode,
+            AddMode::Source => attr.st.st_mode as mode_t & 0o7777,
+        };
+
+        let rdevmajor: dev_t;
+        let rdevminor: dev_t;
+
+        // Treat symlinks as regular files as symlinks are created by the 'ln TARGET ENTRY' command
+        let mode = if attr.is_file() || attr.is_symlink() {
+            rdevmajor = 0;
+            rdevminor = 0;
+            mode | S_IFREG
+        } else {
+            rdevmajor = unsafe { major(attr.st.st_rdev.as_()) }.as_();
+            rdevminor = unsafe { minor(attr.st.st_rdev.as_()) }.as_();
+            if attr.is_block_device() {
+                mode | S_IFBLK
+            } else if attr.is_char_device() {
+                mode | S_IFCHR
+            } else {
+                return Err(CpioError::Unsupported("unsupported file type"));
+            }
+        };
+
+        // The size field in newc headers is only 32 bits
+        let max_size = max_size.unwrap_or(u32::MAX.into());
+        let size: u64 = attr.st.st_size.as_();
+        if mode & S_IFMT == S_IFREG && size > max_size {
+            return Err(CpioError::TooLarge(size));
+        }
+
+        // The file is only read once its data is needed, see load_files
+        let data = if mode & S_IFMT == S_IFREG {
+            DataSource::FilePath {
+                path: file.to_string(),
+                size,
+                max_size,
+            }
+        } else {
+            DataSource::default()
+        };
+
+        if parents {
+            self.check_parents(path, true)?;
+        }
+        // Update an existing entry in place instead of inserting a new one
+        let entry = self.get_or_insert(path);
+        entry.mode = mode;
+        entry.uid = 0;
+        entry.gid = 0;
+        entry.rdevmajor = rdevmajor;
+        entry.rdevminor = rdevminor;
+        entry.data = data;
+        entry.link = 0;
+        eprintln!("Add file [{}] ({:04o})", path, mode);
+        Ok(())
+    }
+
+    fn add_raw(&mut self, mode: mode_t, path: &str, data: &[u8]) -> CpioResult<()> {
+        if path.ends_with('/') || (self.backslash && path.ends_with('\\')) {
+            return Err(CpioError::InvalidPath(path.to_string()));
+        }
+        let mode = mode | S_IFREG;
+        let entry = self.get_or_insert(path);
+        entry.mode = mode;
+        entry.uid = 0;
+        entry.gid = 0;
+        entry.rdevmajor = 0;
+        entry.rdevminor = 0;
+        entry.data.clear();
+        entry.data.extend_from_slice(data);
+        entry.link = 0;
+        eprintln!("Add file [{}] ({:04o})", path, mode);
+        Ok(())
+    }
+
+    fn mkdir(&mut self, mode: mode_t, dir: &str, parents: bool) -> CpioResult<()> {
+        self.check_parents(dir, parents)?;
+        self.get_or_insert_dir(dir)?.mode = mode | S_IFDIR;
+        eprintln!("Create directory [{}] ({:04o})", dir, mode);
+        Ok(())
+    }
+
+    fn ln(&mut self, src: &str, dst: &str) {
+        self.entries.insert(
+            self.norm(dst),
+            Box::new(CpioEntry {
+                ino: 0,
+                mode: S_IFLNK,
+                uid: 0,
+                gid: 0,
+                rdevmajor: 0,
+                rdevminor: 0,
+                // Symlink targets are stored verbatim, absolute or relative
+                data: src.as_bytes().to_vec().into(),
+                link: 0,
+            }),
+        );
+        eprintln!("Create symlink [{}] -> [{}]", dst, src);
+    }
+
+    fn mv(&mut self, from: &str, to: &str, force: bool) -> CpioResult<()> {
+        let from = self.norm(from);
+        let mut to = self.norm(to);
+        if !self.entries.contains_key(&from) {
+            return Err(CpioError::NotFound(from));
+        }
+        if self
+            .entries
+            .get(&to)
+            .is_some_and(|e| e.mode & S_IFMT == S_IFDIR)
+        {
+            // Move into the existing directory, like shell mv
+            let name = from.rsplit('/').next().unwrap_or(&from);
+            to = format!("{}/{}", to, name);
+        }
+        let prefix = format!("{}/", from);
+        if to == from || to.starts_with(&prefix) {
+            return Err(CpioError::InvalidPath(to));
+        }
+        // A directory takes its contents along
+        let moves: Vec<_> = self
+            .entries
+            .keys()
+            .filter(|name| name.starts_with(&prefix))
+            .map(|name| (name.clone(), format!("{}{}", to, &name[from.len()..])))
+            .chain([(from.clone(), to.clone())])
+            .collect();
+        if !force {
+            if let Some((_, dest)) = moves.iter().find(|(_, d)| self.entries.contains_key(d)) {
+                return Err(CpioError::Exists(dest.clone()));
+            }
+        }
+        // The new name is valid UTF-8, only the rest of the raw names of the contents remains
+        let raw_len = self
+            .raw_names
+            .remove(&from)
+            .map_or(from.len(), |raw| raw.len());
+        for (src, dest) in moves {
+            let entry = self.entries.remove(&src).unwrap();
+            self.raw_names.remove(&dest);
+            if let Some(raw) = self.raw_names.remove(&src) {
+                let raw = [to.as_bytes(), &raw[raw_len..]].concat();
+                self.raw_names.insert(dest.clone(), raw);
+            }
+            self.entries.insert(dest, entry);
+        }
+        eprintln!("Move [{}] -> [{}]", from, to);
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> CpioResult<()> {
+        let from = self.norm(from);
+        let to = self.norm(to);
+        if to.matches('*').count() > from.matches('*').count() {
+            return Err(CpioError::InvalidPath(to));
+        }
+        // Destination -> source
+        let mut renames = BTreeMap::<String, &str>::new();
+        for name in self.entries.keys() {
+            let Some(caps) = glob_captures(&from, name) else {
+                continue;
+            };
+            let mut caps = caps.into_iter();
+            let mut parts = to.split('*');
+            let mut dest = parts.next().unwrap_or_default().to_string();
+            for part in parts {
+                dest.push_str(caps.next().unwrap_or_default());
+                dest.push_str(part);
+            }
+            let dest = norm_path(&dest);
+            if dest.is_empty() {
+                return Err(CpioError::InvalidPath(dest));
+            }
+            if let Some(prev) = renames.insert(dest.clone(), name) {
+                eprintln!(
+                    "Both [{}] and [{}] would be renamed to [{}]",
+                    prev, name, dest
+                );
+                return Err(CpioError::Exists(dest));
+            }
+        }
+        if renames.is_empty() {
+            return Err(CpioError::NotFound(from));
+        }
+        let sources: HashSet<&str> = renames.values().copied().collect();
+        for (dest, src) in &renames {
+            if self.entries.contains_key(dest) && !sources.contains(dest.as_str()) {
+                eprintln!("Renaming [{}] would replace [{}]", src, dest);
+                return Err(CpioError::Exists(dest.clone()));
+            }
+        }
+        // Remove all sources first, so entries can swap or shift names
+        let renames: Vec<_> = renames
+            .into_iter()
+            .map(|(dest, src)| (src.to_string(), dest))
+            .collect();
+        let entries: Vec<_> = renames
+            .iter()
+            .map(|(src, _)| {
+                self.raw_names.remove(src);
+                self.entries.remove(src).unwrap()
+            })
+            .collect();
+        for ((src, dest), entry) in renames.into_iter().zip(entries) {
+            eprintln!("Move [{}] -> [{}]", src, dest);
+            self.raw_names.remove(&dest);
+            self.entries.insert(dest, entry);
+        }
+        Ok(())
+    }
+
+    fn copy_from(&mut self, other: &Cpio, path: &str, dest: Option<&str>) -> CpioResult<()> {
+        let path = self.norm(path);
+        let dest = dest.map_or_else(|| path.clone(), |d| self.norm(d));
+        let mut entry = other
+            .entries
+            .get(&path)
+            .ok_or_else(|| CpioError::NotFound(path.clone()))?
+            .clone();
+        // Hardlink groups are only meaningful within the archive they were loaded from
+        entry.link = 0;
+        // Keep the original name bytes only if the entry is not renamed
+        match other.raw_names.get(&path) {
+            Some(raw) if dest == path => self.raw_names.insert(dest.clone(), raw.clone()),
+            _ => self.raw_names.remove(&dest),
+        };
+        self.entries.insert(dest.clone(), entry);
+        eprintln!("Copy [{}] -> [{}]", path, dest);
+        Ok(())
+    }
+
+    fn normalize_owner(&mut self, uid: uid_t, gid: gid_t) {
+        for entry in self.entries.values_mut() {
+            entry.uid = uid;
+            entry.gid = gid;
+        }
+        eprintln!("Set owner of all entries to [{}:{}]", uid, gid);
+    }
+
+    fn sanitize(&mut self) {
+        // Absolute targets that only make sense on the machine that built the archive
+        const HOST_PATHS: [&str; 4] = ["/home/", "/Users/", "/tmp/", "/root/"];
+        let mut owners = 0;
+        let mut inodes = 0;
+        let mut links = 0;
+        for (name, entry) in self.entries.iter_mut() {
+            if entry.uid != 0 || entry.gid != 0 {
+                entry.uid = 0;
+                entry.gid = 0;
+                owners += 1;
+            }
+            if entry.ino != 0 {
+                entry.ino = 0;
+                inodes += 1;
+            }
+            if entry.mode & S_IFMT == S_IFLNK {
+                let target = String::from_utf8_lossy(&entry.data);
+                if HOST_PATHS.iter().any(|p| target.starts_with(p)) {
+                    eprintln!("Symlink to host path [{}] -> [{}]", name, target);
+                    links += 1;
+                }
+            }
+        }
+        eprintln!("Reset owner of {} entries", owners);
+        eprintln!("Dropped inode number of {} entries", inodes);
+        eprintln!("Found {} symlinks to host paths", links);
+    }
+
+    // Returns whether all entries in the manifest match
+    fn checksum_verify(&self, manifest: &[(Vec<u8>, String)], strict: bool) -> bool {
+        let mut ok = true;
+        let mut hash = [0u8; 32];
+        for (expected, name) in manifest {
+            match self.entries.get(name) {
+                Some(entry) => {
+                    sha256_hash(&entry.data, &mut hash);
+                    if hash.as_slice() != expected.as_slice() {
+                        println!("{}: FAILED", name);
+                        ok = false;
+                    }
+                }
+                None => {
+                    println!("{}: MISSING", name);
+                    ok = false;
+                }
+            }
+        }
+        if strict {
+            let listed: HashSet<_> = manifest.iter().map(|(_, name)| name.as_str()).collect();
+            for (name, entry) in self.iter() {
+                if entry.mode & S_IFMT == S_IFREG && !listed.contains(name) {
+                    println!("{}: UNLISTED", name);
+                    ok = false;
+                }
+            }
+        }
+        ok
+    }
+
+    fn readlink(&self, path: &str) -> CpioResult<()> {
+        let path = self.norm(path);
+        let entry = self
+            .get(&path)
+            .ok_or_else(|| CpioError::NotFound(path.clone()))?;
+        if entry.mode & S_IFMT != S_IFLNK {
+            return Err(CpioError::Unsupported("entry is not a symlink"));
+        }
+        println!("{}", String::from_utf8_lossy(&entry.data));
+        Ok(())
+    }
+
+    fn relink(&mut self, path: &str, target: &str) -> CpioResult<()> {
+        let path = self.norm(path);
+        let entry = self
+            .get_mut(&path)
+            .ok_or_else(|| CpioError::NotFound(path.clone()))?;
+        if entry.mode & S_IFMT != S_IFLNK {
+            return Err(CpioError::Unsupported("entry is not a symlink"));
+        }
+        entry.data = target.as_bytes().to_vec().into();
+        entry.link = 0;
+        eprintln!("Relink [{}] -> [{}]", path, target);
+        Ok(())
+    }
+
+    fn owner_map(
+        &mut self,
+        uids: &HashMap<uid_t, uid_t>,
+        gids: &HashMap<gid_t, gid_t>,
+        strict: bool,
+    ) -> CpioResult<()> {
+        if strict {
+            // Check everything first so nothing is changed on failure
+            for (name, entry) in self.iter() {
+                if entry.uid != 0 && !uids.contains_key(&entry.uid) {
+                    return Err(CpioError::Unmapped(name.to_string(), "uid", entry.uid));
+                }
+                if entry.gid != 0 && !gids.contains_key(&entry.gid) {
+                    return Err(CpioError::Unmapped(name.to_string(), "gid", entry.gid));
+                }
+            }
+        }
+        for (name, entry) in self.entries.iter_mut() {
+            let uid = uids.get(&entry.uid).copied().unwrap_or(entry.uid);
+            let gid = gids.get(&entry.gid).copied().unwrap_or(entry.gid);
+            if uid != entry.uid || gid != entry.gid {
+                eprintln!(
+                    "Remap owner [{}] {}:{} -> {}:{}",
+                    name, entry.uid, entry.gid, uid, gid
+                );
+                entry.uid = uid;
+                entry.gid = gid;
+            }
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, path: &str) -> CpioResult<()> {
+        let path = self.norm(path);
+        let entry = self
+            .get_mut(&path)
+            .ok_or_else(|| CpioError::NotFound(path.clone()))?;
+        if entry.mode & S_IFMT != S_IFREG {
+            return Err(CpioError::Unsupported("entry is not a regular file"));
+        }
+        entry.data.clear();
+        entry.link = 0;
+        eprintln!("Truncate file [{}]", path);
+        Ok(())
+    }
+
+    // Returns the number of replaced entries
+    fn replace_all(&mut self, pattern: &str, data: &[u8]) -> usize {
+        let mut count = 0;
+        for (name, entry) in self.entries.iter_mut() {
+            if entry.mode & S_IFMT != S_IFREG || !glob_match(pattern, name) {
+                continue;
+            }
+            entry.data.clear();
+            entry.data.extend_from_slice(data);
+            entry.link = 0;
+            eprintln!("Replace file [{}]", name);
+            count += 1;
+        }
+        count
+    }
+
+    // Move all entries under dir, rewriting symlink targets that would no longer point to
+    // the same entry. Returns the rewritten symlinks as (entry, old target, new target).
+    fn mount_at(&mut self, dir: &str, rebase_absolute: bool) -> Vec<(String, String, String)> {
+        let dir = norm_path(dir);
+        if dir.is_empty() {
+            return Vec::new();
+        }
+        let mut rewrites = Vec::new();
+        for (name, mut entry) in take(&mut self.entries) {
+            let to = format!("{}/{}", dir, name);
+            if entry.mode & S_IFMT == S_IFLNK {
+                let target = String::from_utf8_lossy(&entry.data).into_owned();
+                let parent = name.rsplit_once('/').map_or("", |(p, _)| p);
+                let new = if let Some(abs) = target.strip_prefix('/') {
+                    rebase_absolute.then(|| format!("/{}/{}", dir, norm_path(abs)))
+                } else if escapes_root(parent, &target) {
+                    // ".." at the root stays at the root, which is no longer the case
+                    // once mounted; point to where the target resolved to originally
+                    let resolved = norm_path(&format!("{}/{}", parent, target));
+                    Some(relative_path(parent, &resolved))
+                } else {
+                    None
+                };
+                if let Some(new) = new {
+                    entry.data = new.clone().into_bytes().into();
+                    rewrites.push((to.clone(), target, new));
+                }
+            }
+            self.entries.insert(to, entry);
+        }
+        self.raw_names = take(&mut self.raw_names)
+            .into_iter()
+            .map(|(k, v)| {
+                (
+                    format!("{}/{}", dir, k),
+                    [dir.as_bytes(), b"/".as_slice(), v.as_slice()].concat(),
+                )
+            })
+            .collect();
+        let ends = dir.match_indices('/').map(|(i, _)| i);
+        for end in ends.chain([dir.len()]) {
+            // Everything is under dir now, so nothing is in the way
+            self.get_or_insert(&dir[..end]).mode = S_IFDIR | 0o755;
+        }
+        rewrites
+    }
+
+    pub fn merge(&mut self, other: Cpio, strategy: MergeStrategy) -> CpioResult<()> {
+        let (mut added, mut overwritten, mut skipped) = (0, 0, 0);
+        // Directories merge their contents instead of conflicting
+        let conflicts = |ours: &CpioEntry, theirs: &CpioEntry| {
+            ours.mode & S_IFMT != S_IFDIR || theirs.mode & S_IFMT != S_IFDIR
+        };
+        if strategy == MergeStrategy::Error {
+            // Check every entry first, so a collision leaves the archive untouched
+            let collision = other
+                .entries
+                .iter()
+                .find(|(name, entry)| self.entries.get(*name).is_some_and(|e| conflicts(e, entry)));
+            if let Some((name, _)) = collision {
+                return Err(CpioError::Exists(name.clone()));
+            }
+        }
+        let mut raw_names = other.raw_names;
+        for (name, mut entry) in other.entries {
+            match self.entries.get(&name) {
+                None => added += 1,
+                Some(e) if !conflicts(e, &entry) => {
+                    skipped += 1;
+                    continue;
+                }
+                Some(_) => match strategy {
+                    MergeStrategy::Overwrite => overwritten += 1,
+                    MergeStrategy::KeepExisting => {
+                        skipped += 1;
+                        continue;
+                    }
+                    // Rejected before anything was merged
+                    MergeStrategy::Error => unreachable!(),
+                },
+            }
+            match raw_names.remove(&name) {
+                Some(raw) => self.raw_names.insert(name.clone(), raw),
+                None => self.raw_names.remove(&name),
+            };
+            // Hardlink groups are only meaningful within the archive they were loaded from
+            entry.link = 0;
+            self.entries.insert(name, entry);
+        }
+        eprintln!(
+            "Merged entries: {} added, {} overwritten, {} skipped",
+            added, overwritten, skipped
+        );
+        Ok(())
+    }
+
+    // The entries ls prints with the same arguments, along with their displayed names
+    fn ls_entries(&self, args: &List) -> Vec<(String, &CpioEntry)> {
+        let max_depth = match args.max_depth {
+            Some(depth) => Some(depth),
+            None if args.recursive => None,
+            None => Some(1),
+        };
+        let path = self.norm(&args.path);
+        let mut list = Vec::new();
+        for (name, entry) in self.iter() {
+            if !type_matches(&args.types, entry) {
+                continue;
+            }
+            let rest = match strip_dir(name, &path) {
+                Some(rest) => rest,
+                None => continue,
+            };
+            if let Some(depth) = max_depth {
+                let level = if rest.is_empty() {
+                    0
+                } else {
+                    rest.matches('/').count() + 1
+                };
+                if level > depth {
+                    continue;
+                }
+            }
+            let name = if args.absolute {
+                "/".to_string() + name
+            } else if args.relative {
+                if rest.is_empty() {
+                    ".".to_string()
+                } else {
+                    rest.to_string()
+                }
+            } else {
+                name.to_string()
+            };
+            list.push((name, entry));
+        }
+        match args.sort {
+            ListSort::Name => {}
+            ListSort::Size => list.sort_by(|a, b| b.1.data.len().cmp(&a.1.data.len())),
+            ListSort::Mode => list.sort_by_key(|(_, e)| e.mode),
+        }
+        list
+    }
+
+    fn ls(&self, args: &List) {
+        let list = self.ls_entries(args);
+        if args.inode {
+            // Entries sharing the same inode number are hardlinks
+            let mut nlink = HashMap::<u32, usize>::new();
+            for entry in self.entries.values() {
+                if entry.ino != 0 {
+                    *nlink.entry(entry.ino).or_default() += 1;
+                }
+            }
+            for (name, entry) in list {
+                let n = nlink.get(&entry.ino).copied().unwrap_or(1);
+                let display = entry.display(args.resolve_ids, args.bytes);
+                println!("{}\t{}\t{}\t{}", entry.ino, n, display, name);
+            }
+        } else {
+            for (name, entry) in list {
+                println!("{}\t{}", entry.display(args.resolve_ids, args.bytes), name);
+            }
+        }
+    }
+
+    fn grep(&self, pattern: &str) -> bool {
+        let pattern = pattern.as_bytes();
+        if pattern.is_empty() {
+            return false;
+        }
+        let mut found = false;
+        for (name, entry) in self.iter() {
+            if entry.mode & S_IFMT != S_IFREG {
+                continue;
+            }
+            let mut pos = 0_usize;
+            while let Some(off) = entry.data[pos..].find(pattern) {
+                println!("{}:{}", name, pos + off);
+                found = true;
+                pos += off + 1;
+            }
+        }
+        found
+    }
+}
+
+#[derive(Copy, Clone)]
+#[repr(i32)]
+enum CpioStatus {
+    MagiskPatched = 1 << 0,
+    Unsupported = 1 << 1,
+    SonyInit = 1 << 2,
+    HasBackup = 1 << 3,
+}
+
+impl CpioStatus {
+    const ALL: [CpioStatus; 4] = [
+        CpioStatus::MagiskPatched,
+        CpioStatus::Unsupported,
+        CpioStatus::SonyInit,
+        CpioStatus::HasBackup,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            CpioStatus::MagiskPatched => "magisk",
+            CpioStatus::Unsupported => "unsupported",
+            CpioStatus::SonyInit => "sony_init",
+            CpioStatus::HasBackup => "has_backup",
+        }
+    }
+
+    // Names of all flags set in the status bitmask
+    fn names(status: i32) -> impl Iterator<Item = &'static str> {
+        CpioStatus::ALL
+            .into_iter()
+            .filter(move |f| status & (*f as i32) != 0)
+            .map(|f| f.name())
+    }
+}
+
+impl Cpio {
+    fn patch(&mut self, keep_verity: bool, keep_force_encrypt: bool) {
+        eprintln!(
+            "Patch with flag KEEPVERITY=[{}] KEEPFORCEENCRYPT=[{}]",
+            keep_verity, keep_force_encrypt
+        );
+        self.entries.retain(|name, entry| {
+            patch_entry(
+                name,
+                entry.mode,
+                &mut entry.data,
+                keep_verity,
+                keep_force_encrypt,
+            )
+        });
+    }
+
+    fn print_patch_plan(&self, keep_verity: bool, keep_force_encrypt: bool) {
+        eprintln!(
+            "Patch plan with flag KEEPVERITY=[{}] KEEPFORCEENCRYPT=[{}]",
+            keep_verity, keep_force_encrypt
+        );
+        for (name, entry) in self.iter() {
+            let mut data = entry.data.clone();
+            if !patch_entry(name, entry.mode, &mut data, keep_verity, keep_force_encrypt) {
+                println!("Remove [{}]", name);
+            } else if data != entry.data {
+                println!("Modify [{}]", name);
+            }
+        }
+    }
+
+    fn test(&self, verbose: bool) -> i32 {
+        for file in [
+            "sbin/launch_daemonsu.sh",
+            "sbin/su",
+            "init.xposed.rc",
+            "boot/sbin/launch_daemonsu.sh",
+        ] {
+            if self.exists(file) {
+                if verbose {
+                    eprintln!("Found [{}]: patched by unsupported programs", file);
+                }
+                return CpioStatus::Unsupported as i32;
+            }
+        }
+        let mut ret = 0;
+        for file in [
+            ".backup/.magisk",
+            "init.magisk.rc",
+            "overlay/init.magisk.rc",
+        ] {
+            if self.exists(file) {
+                if verbose {
+                    eprintln!("Found [{}]: patched by Magisk", file);
+                }
+                ret |= CpioStatus::MagiskPatched as i32;
+                break;
+            }
+        }
+        if self.exists("init.real") {
+            if verbose {
+                eprintln!("Found [init.real]: Sony init wrapper, run `sony` before patching");
+            }
+            ret |= CpioStatus::SonyInit as i32;
+        }
+        if self.has_backup() {
+            if verbose {
+                eprintln!("Found [.backup]: stock ramdisk backup, `restore` is possible");
+            }
+            ret |= CpioStatus::HasBackup as i32;
+        }
+        ret
+    }
+
+    fn has_backup(&self) -> bool {
+        self.entries.keys().any(|k| k.starts_with(".backup/"))
+    }
+
+    fn sony(&mut self) {
+        let mut found = false;
+        if self.exists("sbin/ric") {
+            self.rm("sbin/ric", false);
+            found = true;
+        }
+        if let Some(entry) = self.entries.remove("init.real") {
+            self.raw_names.remove("init.real");
+            self.raw_names.remove("init");
+            self.entries.insert("init".to_string(), entry);
+            eprintln!("Restore [init.real] -> [init]");
+            found = true;
+        }
+        if !found {
+            eprintln!("No Sony specific entries found");
+        }
+    }
+
+    fn restore(&mut self) -> CpioResult<()> {
+        let mut backups = HashMap::<String, Box<CpioEntry>>::new();
+        let mut backup_raw_names = HashMap::new();
+        let mut rm_list = String::new();
+        let raw_names = &mut self.raw_names;
+        self.entries
+            .extract_if(|name, _| name.starts_with(".backup/"))
+            .for_each(|(name, mut entry)| {
+                let raw = raw_names.remove(&name);
+                if name == ".backup/.rmlist" {
+                    if let Ok(data) = str::from_utf8(&entry.data) {
+                        rm_list.push_str(data);
+                    }
+                } else if name != ".backup/.magisk" {
+                    let xz = name.ends_with(".xz") && entry.decompress();
+                    let new_name = if xz {
+                        &name[8..name.len() - 3]
+                    } else {
+                        &name[8..]
+                    };
+                    eprintln!("Restore [{}] -> [{}]", name, new_name);
+                    if let Some(raw) = raw {
+                        let end = raw.len() - if xz { 3 } else { 0 };
+                        backup_raw_names.insert(new_name.to_string(), raw[8..end].to_vec());
+                    }
+                    backups.insert(new_name.to_string(), entry);
+                }
+            });
+        self.rm(".backup", false);
+        if rm_list.is_empty() && backups.is_empty() {
+            self.entries.clear();
+            self.raw_names.clear();
+            return Ok(());
+        }
+        for rm in rm_list.split('\0') {
+            if !rm.is_empty() {
+                self.rm(rm, false);
+            }
+        }
+        for name in backups.keys() {
+            self.raw_names.remove(name);
+        }
+        self.raw_names.extend(backup_raw_names);
+        self.entries.extend(backups);
+
+        Ok(())
+    }
+
+    // Restore the entries Magisk adds, replaces or patches from the stock ramdisk at source,
+    // keeping everything else. Entries only found in source are reported, not added.
+    fn restore_from(&mut self, source: &mut String) -> CpioResult<()> {
+        let source = Utf8CStr::from_string(source);
+        let mut o = Cpio::load_from_file(source, &LoadOptions::default())?;
+        o.rm(".backup", true);
+        let mut touched = BTreeSet::new();
+        // What the embedded backup lists, unless it was stripped
+        for (name, entry) in &self.entries {
+            if name == ".backup/.rmlist" {
+                if let Ok(list) = str::from_utf8(&entry.data) {
+                    let added = list.split('\0').filter(|s| !s.is_empty());
+                    touched.extend(added.map(norm_path));
+                }
+            } else if let Some(name) = name.strip_prefix(".backup/") {
+                if name == ".magisk" {
+                    continue;
+                }
+                touched.insert(name.to_string());
+                if let Some(name) = name.strip_suffix(".xz") {
+                    touched.insert(name.to_string());
+                }
+            }
+        }
+        // What Magisk always adds, replaces or patches
+        let magisk = |name: &str| {
+            strip_dir(name, ".backup").is_some()
+                || strip_dir(name, "overlay.d").is_some()
+                || matches!(
+                    name,
+                    "init" | "init.magisk.rc" | "overlay/init.magisk.rc" | "verity_key"
+                )
+                || name.starts_with("fstab")
+        };
+        let names = self.entries.keys().chain(o.entries.keys());
+        touched.extend(names.filter(|name| magisk(name)).cloned());
+
+        for name in touched {
+            match o.entries.remove(&name) {
+                Some(stock) => {
+                    if self.entries.get(&name) != Some(&stock) {
+                        eprintln!("Restore [{}]", name);
+                    }
+                    match o.raw_names.remove(&name) {
+                        Some(raw) => self.raw_names.insert(name.clone(), raw),
+                        None => self.raw_names.remove(&name),
+                    };
+                    self.entries.insert(name, stock);
+                }
+                None => {
+                    if self.entries.remove(&name).is_some() {
+                        eprintln!("Remove [{}]", name);
+                    }
+                    self.raw_names.remove(&name);
+                }
+            }
+        }
+        for name in o.entries.keys() {
+            if !self.entries.contains_key(name) {
+                eprintln!("Only in source [{}]", name);
+            }
+        }
+        Ok(())
+    }
+
+    // Only entries under the directory path are compared
+    fn diff<'a>(&'a self, other: &'a Cpio, ignore: DiffIgnore, path: &str) -> CpioDiff<'a> {
+        let mut changes = Vec::new();
+        for (name, entry) in self.iter() {
+            if strip_dir(name, path).is_none() {
+                continue;
+            }
+            let o = match other.entries.get(name) {
+                Some(o) => o,
+                None => {
+                    changes.push(('-', name, vec![]));
+                    continue;
+                }
+            };
+            let mut attrs = Vec::new();
+            if entry.mode & S_IFMT != o.mode & S_IFMT {
+                attrs.push("type");
+            }
+            if !ignore.mode && entry.mode & !S_IFMT != o.mode & !S_IFMT {
+                attrs.push("mode");
+            }
+            if !ignore.uid && entry.uid != o.uid {
+                attrs.push("uid");
+            }
+            if !ignore.gid && entry.gid != o.gid {
+                attrs.push("gid");
+            }
+            if (entry.rdevmajor, entry.rdevminor) != (o.rdevmajor, o.rdevminor) {
+                attrs.push("rdev");
+            }
+            if entry.data != o.data {
+                attrs.push("data");
+            }
+            if !attrs.is_empty() {
+                changes.push(('M', name, attrs));
+            }
+        }
+        for name in other.entries.keys() {
+            if strip_dir(name, path).is_some() && !self.entries.contains_key(name) {
+                changes.push(('+', name, vec![]));
+            }
+        }
+        CpioDiff { changes }
+    }
+
+    fn backup(&mut self, origin: &mut String, skip_compress: bool) -> CpioResult<()> {
+        let mut backups = HashMap::<String, Box<CpioEntry>>::new();
+        let mut rm_list = String::new();
+        backups.insert(
+            ".backup".to_string(),
+            Box::new(CpioEntry {
+                ino: 0,
+                mode: S_IFDIR,
+                uid: 0,
+                gid: 0,
+                rdevmajor: 0,
+                rdevminor: 0,
+                data: DataSource::default(),
+                link: 0,
+            }),
+        );
+        let origin = Utf8CStr::from_string(origin);
+        let mut o = Cpio::load_from_file(origin, &LoadOptions::default())?;
+        o.rm(".backup", true);
+        self.rm(".backup", true);
+        let mut backup_raw_names = HashMap::new();
+
+        let mut lhs = o.entries.into_iter().peekable();
+        let mut rhs = self.entries.iter().peekable();
+
+        loop {
+            enum Action<'a> {
+                Backup(String, Box<CpioEntry>),
+                Record(&'a String),
+                Noop,
+            }
+            let action = match (lhs.peek(), rhs.peek()) {
+                (Some((l, _)), Some((r, re))) => match l.as_str().cmp(r.as_str()) {
+                    Ordering::Less => {
+                        let (l, le) = lhs.next().unwrap();
+                        Action::Backup(l, le)
+                    }
+                    Ordering::Greater => Action::Record(rhs.next().unwrap().0),
+                    Ordering::Equal => {
+                        let (l, le) = lhs.next().unwrap();
+                        let action = if re.data != le.data {
+                            Action::Backup(l, le)
+                        } else {
+                            Action::Noop
+                        };
+                        rhs.next();
+                        action
+                    }
+                },
+                (Some(_), None) => {
+                    let (l, le) = lhs.next().unwrap();
+                    Action::Backup(l, le)
+                }
+                (None, Some(_)) => Action::Record(rhs.next().unwrap().0),
+                (None, None) => {
+                    break;
+                }
+            };
+            match action {
+                Action::Backup(name, mut entry) => {
+                    // Hardlink groups of the original archive mean nothing here
+                    entry.link = 0;
+                    let backup = if !skip_compress && entry.compress() {
+                        format!(".backup/{}.xz", name)
+                    } else {
+                        format!(".backup/{}", name)
+                    };
+                    eprintln!("Backup [{}] -> [{}]", name, backup);
+                    if let Some(raw) = o.raw_names.get(&name) {
+                        let suffix = &backup.as_bytes()[8 + name.len()..];
+                        let raw = [b".backup/".as_slice(), raw.as_slice(), suffix].concat();
+                        backup_raw_names.insert(backup.clone(), raw);
+                    }
+                    backups.insert(backup, entry);
+                }
+                Action::Record(name) => {
+                    eprintln!("Record new entry: [{}] -> [.backup/.rmlist]", name);
+                    rm_list.push_str(&format!("{}\0", name));
+                }
+                Action::Noop => {}
+            }
+        }
+        if !rm_list.is_empty() {
+            backups.insert(
+                ".backup/.rmlist".to_string(),
+                Box::new(CpioEntry {
+                    ino: 0,
+                    mode: S_IFREG,
+                    uid: 0,
+                    gid: 0,
+                    rdevmajor: 0,
+                    rdevminor: 0,
+                    data: rm_list.as_bytes().to_vec().into(),
+                    link: 0,
+                }),
+            );
+        }
+        self.raw_names.extend(backup_raw_names);
+        self.entries.extend(backups);
+
+        Ok(())
+    }
+}
+
+// Header fields of any of the supported formats
+#[derive(Copy, Clone)]
+struct RawHeader {
+    format: CpioFormat,
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    rdevmajor: u32,
+    rdevminor: u32,
+    name_sz: usize,
+    file_sz: usize,
+}
+
+impl RawHeader {
+    // Parse the header at the start of data, detecting its format from the magic.
+    // Returns the header and its size in bytes.
+    fn parse(data: &[u8]) -> CpioResult<(Self, usize)> {
+        match data.get(..6) {
+            Some(b"070701") | Some(b"070702") => Self::parse_newc(data),
+            Some(b"070707") => Self::parse_odc(data),
+            _ => match data.get(..2) {
+                Some([0xc7, 0x71]) => Self::parse_bin(data, u16::from_le_bytes),
+                Some([0x71, 0xc7]) => Self::parse_bin(data, u16::from_be_bytes),
+                _ => Err(CpioError::BadFormat("invalid cpio magic")),
+            },
+        }
+    }
+
+    fn parse_newc(data: &[u8]) -> CpioResult<(Self, usize)> {
+        let hdr_sz = size_of::<CpioHeader>();
+        let hdr = from_bytes::<CpioHeader>(slice_at(data, 0, hdr_sz)?);
+        let format = if &hdr.magic == b"070702" {
+            CpioFormat::NewcCrc
+        } else {
+            CpioFormat::Newc
+        };
+        let raw = RawHeader {
+            format,
+            ino: x8u(&hdr.ino)?,
+            mode: x8u(&hdr.mode)?,
+            uid: x8u(&hdr.uid)?,
+            gid: x8u(&hdr.gid)?,
+            nlink: x8u(&hdr.nlink)?,
+            rdevmajor: x8u(&hdr.rdevmajor)?,
+            rdevminor: x8u(&hdr.rdevminor)?,
+            name_sz: x8u(&hdr.namesize)? as usize,
+            file_sz: x8u(&hdr.filesize)? as usize,
+        };
+        Ok((raw, hdr_sz))
+    }
+
+    fn parse_odc(data: &[u8]) -> CpioResult<(Self, usize)> {
+        // magic[6] dev[6] ino[6] mode[6] uid[6] gid[6] nlink[6] rdev[6] mtime[11]
+        // namesize[6] filesize[11], all in octal
+        let hdr = slice_at(data, 0, 76)?;
+        let field = |off: usize, len: usize| octal(&hdr[off..(off + len)]);
+        let rdev = field(42, 6)?;
+        let raw = RawHeader {
+            format: CpioFormat::Odc,
+            ino: field(12, 6)? as u32,
+            mode: field(18, 6)? as u32,
+            uid: field(24, 6)? as u32,
+            gid: field(30, 6)? as u32,
+            nlink: field(36, 6)? as u32,
+            rdevmajor: (rdev >> 8) as u32,
+            rdevminor: (rdev & 0xff) as u32,
+            name_sz: field(59, 6)? as usize,
+            file_sz: field(65, 11)? as usize,
+        };
+        Ok((raw, hdr.len()))
+    }
+
+    fn parse_bin(data: &[u8], short: fn([u8; 2]) -> u16) -> CpioResult<(Self, usize)> {
+        // 13 shorts: magic dev ino mode uid gid nlink rdev mtime[2] namesize filesize[2]
+        let hdr = slice_at(data, 0, 26)?;
+        let field = |i: usize| u32::from(short([hdr[i * 2], hdr[i * 2 + 1]]));
+        let raw = RawHeader {
+            format: CpioFormat::Bin,
+            ino: field(2),
+            mode: field(3),
+            uid: field(4),
+            gid: field(5),
+            nlink: field(6),
+            rdevmajor: field(7) >> 8,
+            rdevminor: field(7) & 0xff,
+            name_sz: field(10) as usize,
+            file_sz: (field(11) << 16 | field(12)) as usize,
+        };
+        Ok((raw, hdr.len()))
+    }
+}
+
+// Location of a single entry within the raw archive data
+struct EntryRecord<'a> {
+    hdr: RawHeader,
+    name: Cow<'a, [u8]>,
+    file: Cow<'a, [u8]>,
+    link: u32,
+}
+
+// Normalized name, entry, and the original name bytes if they are not valid UTF-8
+type BuiltEntry = (String, Box<CpioEntry>, Option<Vec<u8>>);
+
+impl EntryRecord<'_> {
+    fn build(&mut self) -> CpioResult<BuiltEntry> {
+        let name = CStr::from_bytes_with_nul(&self.name)
+            .map_err(|_| CpioError::BadFormat("invalid entry name"))?
+            .to_bytes();
+        // Names are raw bytes in cpio; keep the original ones if they are not valid UTF-8
+        let (name, raw) = match str::from_utf8(name) {
+            Ok(name) => (norm_path(name), None),
+            Err(_) => (
+                norm_path(&String::from_utf8_lossy(name)),
+                Some(norm_path_bytes(name)),
+            ),
+        };
+        let entry = Box::new(CpioEntry {
+            ino: self.hdr.ino,
+            mode: self.hdr.mode.as_(),
+            uid: self.hdr.uid.as_(),
+            gid: self.hdr.gid.as_(),
+            rdevmajor: self.hdr.rdevmajor.as_(),
+            rdevminor: self.hdr.rdevminor.as_(),
+            // Data read from a stream is already owned and moved, not copied
+            data: take(&mut self.file).into_owned().into(),
+            link: self.link,
+        });
+        Ok((name, entry, raw))
+    }
+
+    fn build_all(records: &mut [Self]) -> CpioResult<Vec<BuiltEntry>> {
+        records.iter_mut().map(Self::build).collect()
+    }
+}
+
+impl CpioEntry {
+    fn display(&self, resolve_ids: bool, bytes: bool) -> EntryFmt {
+        EntryFmt {
+            entry: self,
+            resolve_ids,
+            bytes,
+        }
+    }
+
+    pub fn file_type(&self) -> Option<CpioType> {
+        CpioType::from_mode(self.mode)
+    }
+
+    pub fn ino(&self) -> u32 {
+        self.ino
+    }
+
+    // The full mode, including the file type bits
+    pub fn mode(&self) -> mode_t {
+        self.mode
+    }
+
+    pub fn uid(&self) -> uid_t {
+        self.uid
+    }
+
+    pub fn gid(&self) -> gid_t {
+        self.gid
+    }
+
+    // The (major, minor) device numbers of block and character devices
+    pub fn rdev(&self) -> (dev_t, dev_t) {
+        (self.rdevmajor, self.rdevminor)
+    }
+
+    // File contents, or the target of a symlink
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // JSON: {"name": string, "type": "-"|"d"|"l"|"b"|"c"|null, "mode": number,
+    //        "uid": number, "gid": number, "rdev": [major, minor], "size": number,
+    //        "ino": number, "target": string|null}
+    // type is the letter printed by ls, mode only has the permission bits, and target is
+    // only set for symlinks
+    fn to_json(&self, name: &str) -> Json {
+        let target = self.mode & S_IFMT == S_IFLNK;
+        let target = target.then(|| String::from_utf8_lossy(&self.data).into_owned());
+        Json::Object(vec![
+            ("name", name.into()),
+            ("type", self.file_type().map(|t| t.to_string()).into()),
+            ("mode", u64::from(self.mode & 0o7777).into()),
+            ("uid", u64::from(self.uid).into()),
+            ("gid", u64::from(self.gid).into()),
+            (
+                "rdev",
+                vec![u64::from(self.rdevmajor), u64::from(self.rdevminor)].into(),
+            ),
+            ("size", self.data.len().into()),
+            ("ino", u64::from(self.ino).into()),
+            ("target", target.into()),
+        ])
+    }
+
+    pub(crate) fn compress(&mut self) -> bool {
+        if self.mode & S_IFMT != S_IFREG {
+            return false;
+        }
+        let mut compressed = Vec::new();
+        if !xz(&self.data, 9, &mut compressed) {
+            eprintln!("xz compression failed");
+            return false;
+        }
+        self.data = compressed.into();
+        true
+    }
+
+    pub(crate) fn decompress(&mut self) -> bool {
+        if self.mode & S_IFMT != S_IFREG {
+            return false;
+        }
+        let mut decompressed = Vec::new();
+        if !unxz(&self.data, &mut decompressed) {
+            eprintln!("xz decompression failed");
+            return false;
+        }
+        self.data = decompressed.into();
+        true
+    }
+}
+
+// Well-known Android IDs, see system/core/libcutils/include/private/android_filesystem_config.h
+fn android_id_name(id: u32) -> Option<&'static str> {
+    match id {
+        0 => Some("root"),
+        1000 => Some("system"),
+        1001 => Some("radio"),
+        1002 => Some("bluetooth"),
+        1003 => Some("graphics"),
+        1004 => Some("input"),
+        1005 => Some("audio"),
+        1006 => Some("camera"),
+        1007 => Some("log"),
+        1010 => Some("wifi"),
+        1013 => Some("media"),
+        1017 => Some("keystore"),
+        1021 => Some("gps"),
+        2000 => Some("shell"),
+        9999 => Some("nobody"),
+        _ => None,
+    }
+}
+
+struct EntryFmt<'a> {
+    entry: &'a CpioEntry,
+    resolve_ids: bool,
+    bytes: bool,
+}
+
+impl EntryFmt<'_> {
+    fn id(&self, id: u32) -> String {
+        match android_id_name(id) {
+            Some(name) if self.resolve_ids => name.to_string(),
+            _ => id.to_string(),
+        }
+    }
+
+    fn size(&self) -> String {
+        let len = self.entry.data.len();
+        if self.bytes {
+            len.to_string()
+        } else {
+            Size::from_bytes(len)
+                .format()
+                .with_style(Style::Abbreviated)
+                .with_base(Base::Base10)
+                .to_string()
+        }
+    }
+}
+
+impl Display for CpioEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.display(false, false).fmt(f)
+    }
+}
+
+impl Display for EntryFmt<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let e = self.entry;
+        write!(
+            f,
+            "{}{}{}{}{}{}{}{}{}{}\t{}\t{}\t{}\t{}:{}",
+            e.file_type()
+                .map_or_else(|| "?".to_string(), |t| t.to_string()),
+            if e.mode & S_IRUSR != 0 { "r" } else { "-" },
+            if e.mode & S_IWUSR != 0 { "w" } else { "-" },
+            if e.mode & S_IXUSR != 0 { "x" } else { "-" },
+            if e.mode & S_IRGRP != 0 { "r" } else { "-" },
+            if e.mode & S_IWGRP != 0 { "w" } else { "-" },
+            if e.mode & S_IXGRP != 0 { "x" } else { "-" },
+            if e.mode & S_IROTH != 0 { "r" } else { "-" },
+            if e.mode & S_IWOTH != 0 { "w" } else { "-" },
+            if e.mode & S_IXOTH != 0 { "x" } else { "-" },
+            self.id(e.uid),
+            self.id(e.gid),
+            self.size(),
+            e.rdevmajor,
+            e.rdevminor,
+        )
+    }
+}
+
+// Bumped before this module writes any file, so that a cache never hands out an archive
+// that was rewritten in the meantime, even within the timestamp granularity
+static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+fn note_write() {
+    WRITES.fetch_add(1, atomic::Ordering::Release);
+}
+
+// Recently loaded archives, keyed by path and revalidated against the file's modification
+// time and size. Archives are shared read-only; callers that need to modify one can use
+// Arc::make_mut, which clones the whole archive if it is cached. This is meant for long-lived
+// embedders loading the same archives repeatedly; the CLI always loads from scratch.
+pub struct CpioCache {
+    capacity: usize,
+    // Least recently used first
+    entries: Vec<(String, (SystemTime, u64), Arc<Cpio>)>,
+    // Value of WRITES when the entries were last known to be current
+    writes: usize,
+}
+
+impl CpioCache {
+    pub fn new(capacity: usize) -> Self {
+        CpioCache {
+            capacity,
+            entries: Vec::new(),
+            writes: WRITES.load(atomic::Ordering::Acquire),
+        }
+    }
+
+    pub fn get(&mut self, path: &Utf8CStr) -> CpioResult<Arc<Cpio>> {
+        let writes = WRITES.load(atomic::Ordering::Acquire);
+        if writes != self.writes {
+            self.entries.clear();
+            self.writes = writes;
+        }
+        let meta = fs::metadata(path).io_context("stat", path)?;
+        let stamp = (meta.modified().io_context("stat", path)?, meta.len());
+        if let Some(i) = self.entries.iter().position(|(p, ..)| p == path.as_str()) {
+            let cached = self.entries.remove(i);
+            if cached.1 == stamp {
+                let cpio = cached.2.clone();
+                self.entries.push(cached);
+                return Ok(cpio);
+            }
+        }
+        let cpio = Arc::new(Cpio::load_from_file(path, &LoadOptions::default())?);
+        if self.capacity > 0 {
+            if self.entries.len() >= self.capacity {
+                self.entries.remove(0);
+            }
+            self.entries.push((path.to_string(), stamp, cpio.clone()));
+        }
+        Ok(cpio)
+    }
+
+    // A file rewritten within the timestamp granularity may keep its modification time
+    // and size, so callers writing to a file outside of this module have to invalidate it
+    pub fn invalidate(&mut self, path: &str) {
+        self.entries.retain(|(p, ..)| p != path);
+    }
+}
+
+// Entries are always dumped sorted by name, so there is no order to place an entry in
+fn check_position(after: &Option<String>, before: &Option<String>) -> CpioResult<()> {
+    if after.is_some() || before.is_some() {
+        return Err(CpioError::Unsupported(
+            "--after and --before need an order-preserving dump, entries are sorted by name",
+        ));
+    }
+    Ok(())
+}
+
+// Returns the exit status for commands that only report on the archive, which end the
+// run without writing it, or None if the archive is to be written as usual
+fn exec_action(
+    cpio: &mut Cpio,
+    file: &Utf8CStr,
+    action: &mut CpioAction,
+    progress: bool,
+) -> CpioResult<Option<i32>> {
+    // Only add and pack leave files unread, any other command may look at the data
+    if !matches!(action, CpioAction::Add(_) | CpioAction::Pack(_)) {
+        cpio.load_files()?;
+    }
+    match action {
+        CpioAction::Test(Test { verbose, names }) => {
+            let status = cpio.test(*verbose);
+            if *names {
+                for name in CpioStatus::names(status) {
+                    println!("{}", name);
+                }
+            }
+            return Ok(Some(status));
+        }
+        CpioAction::CopyFrom(CopyFrom { file, path, dest }) => {
+            let other = Cpio::load_from_file(Utf8CStr::from_string(file), &LoadOptions::default())?;
+            cpio.copy_from(&other, path, dest.as_deref())?;
+        }
+        CpioAction::Diff(Diff {
+            ignore,
+            json,
+            path,
+            file,
+        }) => {
+            let other = Cpio::load_from_file(Utf8CStr::from_string(file), &LoadOptions::default())?;
+            let path = path.as_deref().map(|p| cpio.norm(p)).unwrap_or_default();
+            let diff = cpio.diff(&other, *ignore, &path);
+            if *json {
+                println!("{}", diff.to_json());
+            } else {
+                diff.print();
+            }
+            return Ok(Some(if diff.changes.is_empty() { 0 } else { 1 }));
+        }
+        CpioAction::Pack(Pack { dir }) => {
+            // Files are read while dumping, unless a later command needs them
+            let mut packed = Cpio::new();
+            packed.add_tree(Utf8CStr::from_string(dir), "")?;
+            eprintln!("Packed {} entries from [{}]", packed.entries.len(), dir);
+            cpio.entries = packed.entries;
+            cpio.raw_names.clear();
+        }
+        CpioAction::Unpack(Unpack { dir_mode, dir }) => {
+            let opts = ExtractOptions {
+                list: false,
+                dir_mode: *dir_mode,
+                verify: false,
+                sparse: false,
+                progress,
+            };
+            cpio.unpack(dir, &opts)?;
+        }
+        CpioAction::HasBackup(_) => return Ok(Some(if cpio.has_backup() { 0 } else { 1 })),
+        CpioAction::Sony(_) => cpio.sony(),
+        CpioAction::VerifyTree(_) => {
+            if cpio.verify_tree() {
+                return Ok(Some(0));
+            } else {
+                return Ok(Some(1));
+            }
+        }
+        CpioAction::Validate(_) => match cpio.validate() {
+            Ok(()) => return Ok(Some(0)),
+            Err(errors) => {
+                for e in errors {
+                    println!("{}", e);
+                }
+                return Ok(Some(1));
+            }
+        },
+        CpioAction::Split(_) => {
+            split_cpio(file)?;
+            return Ok(Some(0));
+        }
+        CpioAction::Info(Info { json }) => {
+            cpio.info(*json);
+            return Ok(Some(0));
+        }
+        CpioAction::Stat(Stat { json, path }) => {
+            cpio.stat(path, *json)?;
+            return Ok(Some(0));
+        }
+        CpioAction::Restore(Restore { source }) => match source {
+            Some(source) => cpio.restore_from(source)?,
+            None => cpio.restore()?,
+        },
+        CpioAction::Patch(Patch {
+            keep_verity,
+            keep_forceencrypt,
+            print_plan,
+        }) => {
+            let keep_verity = keep_verity.unwrap_or_else(|| check_env("KEEPVERITY"));
+            let keep_force_encrypt =
+                keep_forceencrypt.unwrap_or_else(|| check_env("KEEPFORCEENCRYPT"));
+            if *print_plan {
+                cpio.print_patch_plan(keep_verity, keep_force_encrypt);
+                return Ok(Some(0));
+            }
+            cpio.patch(keep_verity, keep_force_encrypt);
+        }
+        CpioAction::Exists(Exists { path, ignore_case }) => {
+            let found = if *ignore_case {
+                cpio.exists_ignore_case(path)
+            } else {
+                cpio.exists(path)
+            };
+            if found {
+                return Ok(Some(0));
+            } else {
+                return Ok(Some(1));
+            }
+        }
+        CpioAction::Backup(Backup {
+            origin,
+            skip_compress,
+        }) => cpio.backup(origin, *skip_compress)?,
+        CpioAction::Remove(Remove {
+            path,
+            recursive,
+            dry_run: true,
+        }) => {
+            for name in cpio.rm_matches(path, *recursive) {
+                println!("{}", name);
+            }
+            return Ok(Some(0));
+        }
+        CpioAction::Remove(Remove {
+            path, recursive, ..
+        }) => cpio.rm(path, *recursive),
+        CpioAction::PruneEmpty(PruneEmpty { path }) => cpio.prune_empty(path),
+        CpioAction::Move(Move {
+            from,
+            to,
+            force,
+            after,
+            before,
+        }) => {
+            check_position(after, before)?;
+            cpio.mv(from, to, *force)?;
+        }
+        CpioAction::Rename(Rename { from, to }) => cpio.rename(from, to)?,
+        CpioAction::MakeDir(MakeDir { mode, dir, parents }) => cpio.mkdir(*mode, dir, *parents)?,
+        CpioAction::Link(Link { src, dst }) => cpio.ln(src, dst),
+        CpioAction::Add(Add {
+            mode,
+            path,
+            file,
+            max_size,
+            parents,
+            after,
+            before,
+        }) => {
+            check_position(after, before)?;
+            cpio.add(*mode, path, file, *max_size, *parents)?;
+        }
+        CpioAction::Truncate(Truncate { path }) => cpio.truncate(path)?,
+        CpioAction::ReplaceAll(ReplaceAll {
+            allow_empty,
+            pattern,
+            file,
+        }) => {
+            let data = fs::read(&*file).io_context("read", file)?;
+            let count = cpio.replace_all(pattern, &data);
+            if count == 0 && !*allow_empty {
+                return Err(CpioError::NotFound(pattern.clone()));
+            }
+            eprintln!("Replaced {} entries", count);
+        }
+        CpioAction::Merge(Merge {
+            strategy,
+            mount_point,
+            rebase_absolute,
+            dry_run,
+            file,
+        }) => {
+            let mut other =
+                Cpio::load_from_file(Utf8CStr::from_string(file), &LoadOptions::default())?;
+            if let Some(dir) = mount_point {
+                for (name, old, new) in other.mount_at(&cpio.norm(dir), *rebase_absolute) {
+                    if *dry_run {
+                        println!("{}\t{} -> {}", name, old, new);
+                    } else {
+                        eprintln!("Rebase symlink [{}]: [{}] -> [{}]", name, old, new);
+                    }
+                }
+            }
+            if *dry_run {
+                return Ok(Some(0));
+            }
+            cpio.merge(other, *strategy)?;
+        }
+        CpioAction::ReadLink(ReadLink { path }) => {
+            cpio.readlink(path)?;
+            return Ok(Some(0));
+        }
+        CpioAction::Relink(Relink { path, target }) => cpio.relink(path, target)?,
+        CpioAction::OwnerMap(OwnerMap { strict, file }) => {
+            let map = fs::read_to_string(&*file).io_context("read", file)?;
+            let (uids, gids) = parse_owner_map(&map)?;
+            cpio.owner_map(&uids, &gids, *strict)?;
+        }
+        CpioAction::ChecksumVerify(ChecksumVerify { strict, file }) => {
+            let manifest = fs::read_to_string(&*file).io_context("read", file)?;
+            let manifest = parse_manifest(&manifest)?;
+            return Ok(Some(if cpio.checksum_verify(&manifest, *strict) {
+                0
+            } else {
+                1
+            }));
+        }
+        CpioAction::NormalizeOwner(NormalizeOwner { uid, gid }) => cpio.normalize_owner(*uid, *gid),
+        CpioAction::Sanitize(_) => cpio.sanitize(),
+        CpioAction::AddRaw(AddRaw { mode, path, hex }) => {
+            let data = parse_hex(hex).map_err(|_| CpioError::Invalid("hex string", hex.clone()))?;
+            cpio.add_raw(*mode, path, &data)?
+        }
+        CpioAction::Extract(Extract {
+            list,
+            dir_mode,
+            flatten: true,
+            verify,
+            sparse,
+            include,
+            exclude,
+            types,
+            paths,
+        }) => {
+            if paths.len() > 1 {
+                return Err(CpioError::Invalid("arguments", paths.join(" ")));
+            }
+            let dir = paths.first().map_or(".", |s| s.as_str());
+            let filter = EntryFilter {
+                include,
+                exclude,
+                types,
+            };
+            let opts = ExtractOptions {
+                list: *list,
+                dir_mode: *dir_mode,
+                verify: *verify,
+                sparse: *sparse,
+                progress,
+            };
+            cpio.extract_flat(dir, &opts, &filter)?;
+        }
+        CpioAction::Extract(Extract {
+            list,
+            dir_mode,
+            flatten: false,
+            verify,
+            sparse,
+            include,
+            exclude,
+            types,
+            paths,
+        }) => {
+            if !paths.is_empty() && paths.len() != 2 {
+                return Err(CpioError::Invalid("arguments", paths.join(" ")));
+            }
+            let filter = EntryFilter {
+                include,
+                exclude,
+                types,
+            };
+            let mut it = paths.iter_mut();
+            let opts = ExtractOptions {
+                list: *list,
+                dir_mode: *dir_mode,
+                verify: *verify,
+                sparse: *sparse,
+                progress,
+            };
+            cpio.extract(it.next(), it.next(), &opts, &filter)?;
+        }
+        CpioAction::Export(export) => {
+            cpio.export(export)?;
+        }
+        CpioAction::DumpHex(dump_hex) => {
+            cpio.dump_hex(dump_hex)?;
+            return Ok(Some(0));
+        }
+        CpioAction::List(list) => {
+            if list.absolute && list.relative {
+                return Err(CpioError::Invalid(
+                    "options",
+                    "--absolute and --relative are exclusive".to_string(),
+                ));
+            }
+            cpio.ls(list);
+            return Ok(Some(0));
+        }
+        CpioAction::Find(Find {
+            count,
+            types,
+            pattern,
+        }) => {
+            let matches: Vec<_> = cpio
+                .iter()
+                .filter(|(k, e)| glob_match(pattern, k) && type_matches(types, e))
+                .map(|(k, _)| k)
+                .collect();
+            if *count {
+                println!("{}", matches.len());
+            } else {
+                for name in &matches {
+                    println!("{}", name);
+                }
+            }
+            return Ok(Some(if matches.is_empty() { 1 } else { 0 }));
+        }
+        CpioAction::Grep(Grep { pattern }) => {
+            if cpio.grep(pattern) {
+                return Ok(Some(0));
+            } else {
+                return Ok(Some(1));
+            }
+        }
+    };
+    Ok(None)
+}
+
+fn split_cmd(cmd: &str) -> Vec<&str> {
+    cmd.split(' ').filter(|x| !x.is_empty()).collect()
+}
+
+const UNDO_LIMIT: usize = 16;
+
+// Returns whether the archive should be written when the session ends
+fn run_interactive(
+    cpio: &mut Cpio,
+    file: &Utf8CStr,
+    opts: &mut DumpOptions,
+    progress: bool,
+) -> LoggedResult<bool> {
+    let mut undo: Vec<Cpio> = Vec::new();
+    let mut line = String::new();
+    loop {
+        eprint!("cpio> ");
+        line.clear();
+        if io::stdin().read_line(&mut line)? == 0 {
+            eprintln!();
+            return Ok(false);
+        }
+        let cmd = line.trim();
+        if cmd.is_empty() || cmd.starts_with('#') {
+            continue;
+        }
+        match cmd {
+            "help" => {
+                print_cpio_usage();
+                continue;
+            }
+            "save" => {
+                // Keep the session going if the write fails
+                if cpio.dump(file, opts).log().is_ok() {
+                    // The backup should be the archive from before the session
+                    opts.backup_suffix = None;
+                }
+                continue;
+            }
+            "exit" => return Ok(true),
+            "quit" => return Ok(false),
+            "undo" => {
+                match undo.pop() {
+                    Some(prev) => *cpio = prev,
+                    None => eprintln!("Nothing to undo"),
+                }
+                continue;
+            }
+            _ => {}
+        }
+        let mut cli = match CpioCommand::from_args(&["magiskboot", "cpio", file], &split_cmd(cmd)) {
+            Ok(cli) => cli,
+            Err(e) => {
+                eprintln!("{}", e.output);
+                continue;
+            }
+        };
+        // Only commands that change the archive are worth a copy to undo
+        let snapshot = cli.action.mutates().then(|| cpio.clone());
+        match exec_action(cpio, file, &mut cli.action, progress) {
+            Ok(Some(0)) => {}
+            Ok(Some(status)) => eprintln!("status {}", status),
+            Ok(None) => {
+                if let Some(snapshot) = snapshot {
+                    if undo.len() == UNDO_LIMIT {
+                        undo.remove(0);
+                    }
+                    undo.push(snapshot);
+                }
+            }
+            // Drop any partial changes
+            Err(e) => {
+                log_err!("{}", e);
+                if let Some(snapshot) = snapshot {
+                    *cpio = snapshot;
+                }
+            }
+        }
+    }
+}
+
+pub fn cpio_commands(argc: i32, argv: *const *const c_char) -> bool {
+    fn inner(argc: i32, argv: *const *const c_char) -> LoggedResult<()> {
+        if argc < 1 {
+            return Err(log_err!("No arguments"));
+        }
+
+        let cmds = map_args(argc, argv)?;
+
+        let mut cli =
+            CpioCli::from_args(&["magiskboot", "cpio"], &cmds).on_early_exit(print_cpio_usage);
+
+        let file = Utf8CStr::from_string(&mut cli.file);
+        let start = Instant::now();
+        let mut cpio = if FsPath::from(file).exists() {
+            let opts = LoadOptions {
+                threads: cli.threads,
+                progress: cli.progress,
+                limits: cli.limits,
+                lenient: cli.lenient,
+                compression: CpioCompression::forced(),
+            };
+            Cpio::load_from_file(file, &opts)?
+        } else {
+            Cpio::new()
+        };
+        let load_time = start.elapsed();
+        cpio.backslash = cli.backslash;
+        if cli.strict && cpio.garbage() > 0 {
+            return Err(log_err!("unexpected data after the cpio trailer"));
+        }
+        // Check before any command runs, so a bad level never touches incpio
+        if let Some(level) = cli.compress_level {
+            match cpio.compression() {
+                Some(comp) if !comp.levels().contains(&level) => {
+                    return Err(CpioError::InvalidLevel(level, comp).into());
+                }
+                Some(_) => {}
+                None => return Err(log_err!("--compress-level requires a compressed incpio")),
+            }
+        }
+        if !cli.lossy_names {
+            if let Some(name) = cpio.lossy_names().next() {
+                return Err(log_err!(
+                    "entry name [{}] is not valid UTF-8, use --lossy-names",
+                    name
+                ));
+            }
+        }
+        let mut peak_entries = cpio.entries.len();
+        let mut cmd_times = Vec::new();
+        let mut errors: Vec<(String, CpioError)> = Vec::new();
+
+        let mut commands = cli.commands;
+        if let Some(script) = &cli.script {
+            let script = fs::read_to_string(script).io_context("read", script)?;
+            commands.extend(script.lines().map(|l| l.trim().to_string()));
+        }
+        let keep_going = cli.keep_going;
+        let progress = cli.progress;
+
+        for cmd in commands {
+            if cmd.is_empty() || cmd.starts_with('#') {
+                continue;
+            }
+            let mut cli = CpioCommand::from_args(&["magiskboot", "cpio", file], &split_cmd(&cmd))
+                .on_early_exit(print_cpio_usage);
+
+            // A failed command may have changed the archive halfway, keep a copy to restore
+            let snapshot = (keep_going && cli.action.mutates()).then(|| cpio.clone());
+            let start = Instant::now();
+            let result = exec_action(&mut cpio, file, &mut cli.action, progress);
+            let elapsed = start.elapsed();
+            peak_entries = peak_entries.max(cpio.entries.len());
+            match result {
+                Ok(Some(status)) => {
+                    // Failures of earlier commands still fail the run
+                    if report_failures(&errors) {
+                        exit(status.max(1));
+                    }
+                    exit(status);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    if !keep_going {
+                        return Err(e.into());
+                    }
+                    log_err!("{}", e);
+                    // Drop any partial changes
+                    if let Some(snapshot) = snapshot {
+                        cpio = snapshot;
+                    }
+                    errors.push((cmd.clone(), e));
+                }
+            }
+            cmd_times.push((cmd, elapsed));
+        }
+        let mut opts = DumpOptions {
+            format: match cli.out_format {
+                Some(format) => format,
+                None if cli.checksum => CpioFormat::NewcCrc,
+                // Write back in the same format as the input archive
+                None => cpio.format().unwrap_or(CpioFormat::Newc),
+            },
+            inode: cli.inode,
+            level: cli.compress_level,
+            trailer: !cli.no_trailer,
+            progress: cli.progress,
+            prefix: cli.prefix.as_deref().map(norm_path),
+            backup_suffix: cli.backup_suffix,
+            mtime: match cli.mtime {
+                Mtime::Fixed(mtime) => mtime,
+                Mtime::SourceDateEpoch => env::var("SOURCE_DATE_EPOCH")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(|| log_err!("SOURCE_DATE_EPOCH is not set to a valid time"))?,
+            },
+        };
+        let write = !cli.interactive || run_interactive(&mut cpio, file, &mut opts, progress)?;
+        if write {
+            let start = Instant::now();
+            cpio.dump(file, &opts)?;
+            let dump_time = start.elapsed();
+
+            if cli.stats {
+                eprintln!("load\t{:?}", load_time);
+                for (cmd, time) in &cmd_times {
+                    eprintln!("cmd\t{:?}\t{}", time, cmd);
+                }
+                eprintln!("dump\t{:?}", dump_time);
+                eprintln!("entries\t{} (peak {})", cpio.entries.len(), peak_entries);
+                let data: usize = cpio.entries.values().map(|e| e.data.len()).sum();
+                eprintln!("data\t{}", data);
+            }
+        }
+        if report_failures(&errors) {
+            return Err(log_err!(
+                "{} of {} commands failed",
+                errors.len(),
+                cmd_times.len()
+            ));
+        }
+        Ok(())
+    }
+    inner(argc, argv)
+        .log_with_msg(|w| w.write_str("Failed to process cpio"))
+        .is_ok()
+}
+
+// Print one line per failed command as 'failed<TAB>COMMAND<TAB>ERROR', so that scripts can
+// tell which commands failed. Returns whether there were any failures.
+fn report_failures(errors: &[(String, CpioError)]) -> bool {
+    for (cmd, e) in errors {
+        eprintln!("failed\t{}\t{}", cmd, e);
+    }
+    !errors.is_empty()
+}
+
+// Returns false if the entry has to be removed
+fn patch_entry(
+    name: &str,
+    mode: mode_t,
+    data: &mut Vec<u8>,
+    keep_verity: bool,
+    keep_force_encrypt: bool,
+) -> bool {
+    let fstab = (!keep_verity || !keep_force_encrypt)
+        && mode & S_IFMT == S_IFREG
+        && !name.starts_with(".backup")
+        && !name.starts_with("twrp")
+        && !name.starts_with("recovery")
+        && name.starts_with("fstab");
+    if !keep_verity {
+        if fstab {
+            eprintln!("Found fstab file [{}]", name);
+            let len = patch_verity(data.as_mut_slice());
+            if len != data.len() {
+                data.resize(len, 0);
+            }
+        } else if name == "verity_key" {
+            return false;
+        }
+    }
+    if !keep_force_encrypt && fstab {
+        let len = patch_encryption(data.as_mut_slice());
+        if len != data.len() {
+            data.resize(len, 0);
+        }
+    }
+    true
+}
+
+// Skip over blocks of zeros instead of writing them, leaving holes in the file.
+// Filesystems without sparse file support fill the skipped ranges with zeros.
+fn write_sparse(file: &mut File, data: &[u8]) -> io::Result<()> {
+    const BLOCK: usize = 4096;
+    for chunk in data.chunks(BLOCK) {
+        if chunk.iter().all(|b| *b == 0) {
+            file.seek(SeekFrom::Current(chunk.len() as i64))?;
+        } else {
+            file.write_all(chunk)?;
+        }
+    }
+    // Seeking alone does not extend the file over a trailing hole
+    file.set_len(data.len() as u64)
+}
+
+// Check the type, permissions, and content of an extracted file against its entry
+fn verify_extracted(entry: &CpioEntry, out: &FsPath) -> CpioResult<bool> {
+    let attr = out.get_attr().io_context("stat", out)?;
+    let mut ok = true;
+    let mut mismatch = |what: &str| {
+        eprintln!("Mismatched {} of [{}]", what, out);
+        ok = false;
+    };
+    if attr.st.st_mode as mode_t & S_IFMT != entry.mode & S_IFMT {
+        mismatch("type");
+        return Ok(false);
+    }
+    // Symlink permissions are not meaningful
+    if entry.mode & S_IFMT != S_IFLNK && attr.st.st_mode as mode_t & 0o777 != entry.mode & 0o777 {
+        mismatch("mode");
+    }
+    match entry.file_type() {
+        Some(CpioType::Reg) => {
+            let size: u64 = attr.st.st_size.as_();
+            if size != entry.data.len() as u64 {
+                mismatch("size");
+            }
+        }
+        Some(CpioType::Symlink) => {
+            let mut buf = Utf8CStrBufArr::default();
+            out.read_link(&mut buf).io_context("read link", out)?;
+            if buf.as_bytes() != entry.data.as_slice() {
+                mismatch("symlink target");
+            }
+        }
+        Some(CpioType::Block | CpioType::Char) => {
+            let rdevmajor: dev_t = unsafe { major(attr.st.st_rdev.as_()) }.as_();
+            let rdevminor: dev_t = unsafe { minor(attr.st.st_rdev.as_()) }.as_();
+            if rdevmajor != entry.rdevmajor || rdevminor != entry.rdevminor {
+                mismatch("device number");
+            }
+        }
+        Some(CpioType::Dir) | None => {}
+    }
+    Ok(ok)
+}
+
+fn write_entry<W: Write>(
+    file: &mut W,
+    opts: &DumpOptions,
+    name: &[u8],
+    entry: &CpioEntry,
+    ino: u64,
+    nlink: u32,
+    pos: &mut usize,
+) -> CpioResult<()> {
+    let format = opts.format;
+    let overflow =
+        |field| CpioError::FieldOverflow(String::from_utf8_lossy(name).into_owned(), field, format);
+    let name_sz = name.len() + 1;
+    let file_sz = entry.data.len();
+    // The old formats store device numbers in the traditional 16 bit layout
+    let old_rdev = || {
+        if entry.rdevmajor < 0x100 && entry.rdevminor < 0x100 {
+            Ok(u64::from(entry.rdevmajor) << 8 | u64::from(entry.rdevminor))
+        } else {
+            Err(overflow("rdev"))
+        }
+    };
+    match format {
+        CpioFormat::Newc | CpioFormat::NewcCrc => {
+            let (magic, check) = if format == CpioFormat::NewcCrc {
+                let check = entry
+                    .data
+                    .iter()
+                    .fold(0u32, |sum, b| sum.wrapping_add(*b as u32));
+                ("070702", check)
+            } else {
+                ("070701", 0)
+            };
+            // Fields are 32 bit hex numbers
+            let word = |v: u64, field| u32::try_from(v).map_err(|_| overflow(field));
+            let hdr = format!(
+                "{}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+                magic,
+                word(ino, "ino")?,
+                entry.mode,
+                entry.uid,
+                entry.gid,
+                nlink,
+                word(opts.mtime, "mtime")?,
+                word(file_sz as u64, "filesize")?,
+                0,
+                0,
+                entry.rdevmajor,
+                entry.rdevminor,
+                word(name_sz as u64, "namesize")?,
+                check
+            );
+            file.write_all(hdr.as_bytes())?;
+            file.write_all(name)?;
+            file.write_all(&[0])?;
+            pad_after(file, pos, hdr.len() + name_sz, format.align())?;
+            file.write_all(&entry.data)?;
+            pad_after(file, pos, file_sz, format.align())?;
+        }
+        CpioFormat::Odc => {
+            // Fields are octal numbers with a fixed number of digits
+            let fit = |v: u64, digits: u32, field| {
+                if v < 8u64.pow(digits) {
+                    Ok(v)
+                } else {
+                    Err(overflow(field))
+                }
+            };
+            let hdr = format!(
+                "070707{:06o}{:06o}{:06o}{:06o}{:06o}{:06o}{:06o}{:011o}{:06o}{:011o}",
+                0,
+                fit(ino, 6, "ino")?,
+                fit(entry.mode.into(), 6, "mode")?,
+                fit(entry.uid.into(), 6, "uid")?,
+                fit(entry.gid.into(), 6, "gid")?,
+                fit(nlink.into(), 6, "nlink")?,
+                fit(old_rdev()?, 6, "rdev")?,
+                fit(opts.mtime, 11, "mtime")?,
+                fit(name_sz as u64, 6, "namesize")?,
+                fit(file_sz as u64, 11, "filesize")?,
+            );
+            file.write_all(hdr.as_bytes())?;
+            file.write_all(name)?;
+            file.write_all(&[0])?;
+            file.write_all(&entry.data)?;
+            pad_after(file, pos, hdr.len() + name_sz + file_sz, format.align())?;
+        }
+        CpioFormat::Bin => {
+            let short = |v: u64, field| u16::try_from(v).map_err(|_| overflow(field));
+            let file_sz = u32::try_from(file_sz).map_err(|_| overflow("filesize"))?;
+            let mtime = u32::try_from(opts.mtime).map_err(|_| overflow("mtime"))?;
+            let hdr: [u16; 13] = [
+                0o070707,
+                0,
+                short(ino, "ino")?,
+                short(entry.mode.into(), "mode")?,
+                short(entry.uid.into(), "uid")?,
+                short(entry.gid.into(), "gid")?,
+                short(nlink.into(), "nlink")?,
+                short(old_rdev()?, "rdev")?,
+                (mtime >> 16) as u16,
+                mtime as u16,
+                short(name_sz as u64, "namesize")?,
+                (file_sz >> 16) as u16,
+                file_sz as u16,
+            ];
+            for field in hdr {
+                file.write_all(&field.to_le_bytes())?;
+            }
+            file.write_all(name)?;
+            file.write_all(&[0])?;
+            pad_after(file, pos, hdr.len() * 2 + name_sz, format.align())?;
+            file.write_all(&entry.data)?;
+            pad_after(file, pos, entry.data.len(), format.align())?;
+        }
+    }
+    Ok(())
+}
+
+fn split_segments(data: &[u8]) -> CpioResult<Vec<&[u8]>> {
+    let mut segments = Vec::new();
+    let mut start = 0_usize;
+    let mut pos = 0_usize;
+    while pos < data.len() {
+        let (hdr, hdr_sz) = RawHeader::parse(&data[pos..])?;
+        let align = hdr.format.align();
+        pos += hdr_sz;
+        let name = slice_at(data, pos, hdr.name_sz)?;
+        pos = align_after(pos, hdr.name_sz, align)?;
+        pos = align_after(pos, hdr.file_sz, align)?;
+        if name == b"TRAILER!!!\0" {
+            let end = pos.min(data.len());
+            segments.push(&data[start..end]);
+            let mut rest = SliceInput::new(&data[end..]);
+            match next_archive(&mut rest, hdr.format, CpioLimits::default().trailer_scan)? {
+                (true, _) => {
+                    start = end + rest.offset();
+                    pos = start;
+                }
+                (false, _) => return Ok(segments),
+            }
+        }
+    }
+    if start < data.len() {
+        // The last archive is missing its trailer
+        segments.push(&data[start..]);
+    }
+    Ok(segments)
+}
+
+// Each segment is written with the compression of the input archive
+fn split_cpio(path: &Utf8CStr) -> CpioResult<()> {
+    let file = MappedFile::open(path).io_context("read", path)?;
+    let comp = CpioCompression::detect(file.as_ref());
+    let mut buf = Vec::new();
+    let data = match comp {
+        Some(comp) => {
+            if !comp.decompress(file.as_ref(), &mut buf) {
+                return Err(CpioError::BadFormat("decompression failed"));
+            }
+            buf.as_slice()
+        }
+        None => file.as_ref(),
+    };
+    let segments = split_segments(data)?;
+    let prefix = path.strip_suffix(".cpio").unwrap_or(path.as_str());
+    let mut out = Vec::new();
+    for (i, segment) in segments.iter().enumerate() {
+        let name = format!("{}.{}.cpio", prefix, i);
+        eprintln!("Writing segment [{}]", name);
+        note_write();
+        let segment = match comp {
+            Some(comp) => {
+                out.clear();
+                if !comp.compress(segment, comp.default_level(), &mut out) {
+                    return Err(CpioError::Unsupported("compression failed"));
+                }
+                out.as_slice()
+            }
+            None => segment,
+        };
+        File::create(&name)
+            .and_then(|mut f| f.write_all(segment))
+            .io_context("write", &name)?;
+    }
+    eprintln!("Found {} segment(s)", segments.len());
+    Ok(())
+}
+
+fn x8u(x: &[u8; 8]) -> CpioResult<u32> {
+    // parse hex
+    let mut ret = 0u32;
+    let s = str::from_utf8(x).map_err(|_| CpioError::BadFormat("bad cpio header"))?;
+    for c in s.chars() {
+        ret = ret * 16
+            + c.to_digit(16)
+                .ok_or(CpioError::BadFormat("bad cpio header"))?;
+    }
+    Ok(ret)
+}
+
+fn octal(x: &[u8]) -> CpioResult<u64> {
+    x.iter().try_fold(0u64, |ret, c| match c {
+        b'0'..=b'7' => Ok(ret * 8 + u64::from(c - b'0')),
+        _ => Err(CpioError::BadFormat("bad cpio header")),
+    })
+}
+
+// 32-bit FNV-1a
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5_u32, |h, b| {
+        (h ^ *b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+// Match name against a shell-like pattern; '*' also matches across '/'
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let p = pattern.as_bytes();
+    let n = name.as_bytes();
+    let (mut pi, mut ni) = (0, 0);
+    // Position of the last '*' in the pattern and where it started matching in the name
+    let mut star: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        match p.get(pi) {
+            Some(b'*') => {
+                star = Some((pi, ni));
+                pi += 1;
+            }
+            Some(&c) if c == b'?' || c == n[ni] => {
+                pi += 1;
+                ni += 1;
+            }
+            _ => match star {
+                // Let the last '*' consume one more character and retry
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    pi = sp + 1;
+                    ni = sn + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    p[pi..].iter().all(|&c| c == b'*')
+}
+
+// Like glob_match, but returns the text matched by each '*' in order
+fn glob_captures<'a>(pattern: &str, name: &'a str) -> Option<Vec<&'a str>> {
+    fn capture<'a>(pattern: &str, name: &'a str, caps: &mut Vec<&'a str>) -> bool {
+        let mut p = pattern.chars();
+        match p.next() {
+            None => name.is_empty(),
+            Some('*') => {
+                // Try the shortest match first; a failed attempt leaves caps unchanged
+                let ends = name.char_indices().map(|(i, _)| i).chain([name.len()]);
+                for end in ends {
+                    caps.push(&name[..end]);
+                    if capture(p.as_str(), &name[end..], caps) {
+                        return true;
+                    }
+                    caps.pop();
+                }
+                false
+            }
+            Some(c) => {
+                let mut n = name.chars();
+                match n.next() {
+                    Some(x) if c == '?' || c == x => capture(p.as_str(), n.as_str(), caps),
+                    _ => false,
+                }
+            }
+        }
+    }
+    let mut caps = Vec::new();
+    capture(pattern, name, &mut caps).then_some(caps)
+}
+
+// Offsets come from untrusted headers or grow with the output, never let them overflow
+fn align_after(pos: usize, len: usize, align: usize) -> CpioResult<usize> {
+    pos.checked_add(len)
+        .and_then(|x| x.checked_next_multiple_of(align))
+        .ok_or(CpioError::Truncated)
+}
+
+// Advance pos past len bytes just written, and write the zero padding up to align
+fn pad_after<W: Write>(file: &mut W, pos: &mut usize, len: usize, align: usize) -> CpioResult<()> {
+    let end = align_after(*pos, len, align)?;
+    file.write_zeros(end - *pos - len)?;
+    *pos = end;
+    Ok(())
+}
+
+// Skip to the next concatenated archive after a trailer, searching at most scan_limit bytes
+// past the zero padding. A binary archive has to start right after the padding.
+// Returns whether there is one, and the bytes of garbage skipped.
+fn next_archive<'a>(
+    input: &mut impl LoadInput<'a>,
+    format: CpioFormat,
+    scan_limit: usize,
+) -> CpioResult<(bool, usize)> {
+    // Archives are normally only separated by zero padding
+    loop {
+        let data = input.peek(STREAM_CHUNK)?;
+        if data.is_empty() {
+            return Ok((false, 0));
+        }
+        let start = data.iter().position(|b| *b != 0);
+        let zeros = start.unwrap_or(data.len());
+        input.skip(zeros)?;
+        if start.is_some() {
+            break;
+        }
+    }
+    let next = match format {
+        CpioFormat::Newc | CpioFormat::NewcCrc | CpioFormat::Odc => {
+            input.find(0, scan_limit, b"07070")?
+        }
+        // Binary headers are not searched for, their 2 byte magic is too likely in garbage
+        CpioFormat::Bin => match input.peek(2)?.get(..2) {
+            Some([0xc7, 0x71] | [0x71, 0xc7]) => Some(0),
+            _ => None,
+        },
+    };
+    let garbage = |data: &[u8]| data.iter().filter(|b| **b != 0).count();
+    match next {
+        Some(x) => {
+            let skipped = garbage(&input.peek(x)?[..x]);
+            input.skip(x)?;
+            Ok((true, skipped))
+        }
+        None => {
+            if format != CpioFormat::Bin
+                && input.peek(scan_limit.saturating_add(1))?.len() > scan_limit
+            {
+                eprintln!(
+                    "No cpio archive found within {} bytes after trailer, ignoring the rest",
+                    scan_limit
+                );
+            }
+            // Everything left is trailing data, e.g. an appended signature
+            let mut skipped = 0;
+            loop {
+                let data = input.peek(STREAM_CHUNK)?;
+                if data.is_empty() {
+                    break;
+                }
+                let n = data.len();
+                skipped += garbage(data);
+                input.skip(n)?;
+            }
+            Ok((false, skipped))
+        }
+    }
+}
+
+// Read a whole file in one allocation. Never read more than allowed, in case the file grew
+// since it was added.
+fn read_file(path: &str, max_size: u64) -> CpioResult<Vec<u8>> {
+    let mut path = path.to_string();
+    let file = FsPath::from(Utf8CStr::from_string(&mut path));
+    let attr = file.get_attr().io_context("stat", file)?;
+    let size: u64 = attr.st.st_size.as_();
+    let mut data = file_buffer(size.min(max_size))?;
+    file.open(O_RDONLY | O_CLOEXEC)
+        .and_then(|f| f.take(max_size.saturating_add(1)).read_to_end(&mut data))
+        .io_context("read", file)?;
+    if data.len() as u64 > max_size {
+        return Err(CpioError::TooLarge(data.len() as u64));
+    }
+    Ok(data)
+}
+
+// Allocate once for the whole contents of a file, so that reading never grows the buffer
+// and the peak is a single copy of the file. The size comes from stat and may not fit in
+// memory, so fail instead of aborting.
+fn file_buffer(size: u64) -> CpioResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    usize::try_from(size)
+        .ok()
+        .and_then(|n| buf.try_reserve_exact(n).ok())
+        .ok_or(CpioError::TooLarge(size))?;
+    Ok(buf)
+}
+
+fn slice_at(data: &[u8], pos: usize, len: usize) -> CpioResult<&[u8]> {
+    let end = pos.checked_add(len).ok_or(CpioError::Truncated)?;
+    data.get(pos..end).ok_or(CpioError::Truncated)
+}
+
+// Whether resolving target from the directory parent climbs above the root
+fn escapes_root(parent: &str, target: &str) -> bool {
+    let mut depth = parent.split('/').filter(|p| !p.is_empty()).count();
+    for p in target.split('/') {
+        match p {
+            "" | "." => {}
+            ".." if depth == 0 => return true,
+            ".." => depth -= 1,
+            _ => depth += 1,
+        }
+    }
+    false
+}
+
+// The relative path from the directory from to the normalized path to
+fn relative_path(from: &str, to: &str) -> String {
+    let from: Vec<_> = from.split('/').filter(|p| !p.is_empty()).collect();
+    let to: Vec<_> = to.split('/').filter(|p| !p.is_empty()).collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut parts = vec![".."; from.len() - common];
+    parts.extend(&to[common..]);
+    if parts.is_empty() {
+        ".".to_string()
+    } else {
+        parts.join("/")
+    }
+}
+
+fn norm_path(path: &str) -> String {
+    norm_path_sep(path, false)
+}
+
+// Like norm_path, optionally also splitting on '\' for paths copied from Windows
+fn norm_path_sep(path: &str, backslash: bool) -> String {
+    let mut parts = Vec::new();
+    for p in path.split(|c| c == '/' || (backslash && c == '\\')) {
+        match p {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(p),
+        }
+    }
+    parts.join("/")
+}
+
+// Returns the part of name below dir, or an empty string if name is dir itself. Both are
+// normalized paths; every entry is below the empty dir (the root).
+fn strip_dir<'a>(name: &'a str, dir: &str) -> Option<&'a str> {
+    if dir.is_empty() {
+        return Some(name);
+    }
+    match name.strip_prefix(dir)? {
+        "" => Some(""),
+        rest => rest.strip_prefix('/'),
+    }
+}
+
+fn norm_path_bytes(path: &[u8]) -> Vec<u8> {
+    let mut parts = Vec::new();
+    for p in path.split(|b| *b == b'/') {
+        match p {
+            b"" | b"." => {}
+            b".." => {
+                parts.pop();
+            }
+            _ => parts.push(p),
+        }
+    }
+    parts.join(&b'/')
+}
+
+fn parse_mode(s: &str) -> Result<mode_t, String> {
+    mode_t::from_str_radix(s, 8).map_err(|e| e.to_string())
+}
+
+fn parse_add_mode(s: &str) -> Result<AddMode, String> {
+    match s {
+        "-" => Ok(AddMode::Source),
+        _ => parse_mode(s).map(AddMode::Fixed),
+    }
+}
+
+fn parse_out_format(s: &str) -> Result<CpioFormat, String> {
+    match s {
+        "newc" => Ok(CpioFormat::Newc),
+        "crc" => Ok(CpioFormat::NewcCrc),
+        "odc" => Ok(CpioFormat::Odc),
+        "bin" => Ok(CpioFormat::Bin),
+        _ => Err(format!("unknown cpio format {}", s)),
+    }
+}
+
+fn parse_mtime(s: &str) -> Result<Mtime, String> {
+    match s {
+        "zero" => Ok(Mtime::Fixed(0)),
+        "source-date-epoch" => Ok(Mtime::SourceDateEpoch),
+        _ => s
+            .parse()
+            .map(Mtime::Fixed)
+            .map_err(|_| format!("invalid mtime {}", s)),
+    }
+}
+
+fn parse_limits(s: &str) -> Result<CpioLimits, String> {
+    let mut limits = CpioLimits::default();
+    for limit in s.split(',') {
+        let (key, val) = limit
+            .split_once('=')
+            .ok_or_else(|| format!("invalid limit {}", limit))?;
+        let val: usize = val
+            .parse()
+            .map_err(|_| format!("invalid limit {}", limit))?;
+        limits = match key {
+            "entries" => limits.max_entries(val),
+            "data" => limits.max_data(val),
+            "scan" => limits.trailer_scan(val),
+            _ => return Err(format!("unknown limit {}", key)),
+        };
+    }
+    Ok(limits)
+}
+
+fn parse_inode_mode(s: &str) -> Result<InodeMode, String> {
+    match s {
+        "seq" => Ok(InodeMode::Sequential),
+        "keep" => Ok(InodeMode::Keep),
+        "hash" => Ok(InodeMode::Hash),
+        "path" => Ok(InodeMode::Path),
+        _ => Err(format!("unknown inode mode {}", s)),
+    }
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    if s.len() % 2 != 0 || !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("invalid hex string {}", s));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..(i + 2)], 16).map_err(|e| e.to_string()))
+        .collect()
+}
+
+// Each line is [u:|g:]OLD:NEW, without a prefix the pair applies to both uids and gids
+fn parse_owner_map(content: &str) -> CpioResult<(HashMap<uid_t, uid_t>, HashMap<gid_t, gid_t>)> {
+    let mut uids = HashMap::new();
+    let mut gids = HashMap::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (kind, pair) = match line.split_once(':') {
+            Some(("u", pair)) => ("u", pair),
+            Some(("g", pair)) => ("g", pair),
+            _ => ("", line),
+        };
+        let Some((Ok(old), Ok(new))) = pair
+            .split_once(':')
+            .map(|(old, new)| (old.parse::<u32>(), new.parse::<u32>()))
+        else {
+            return Err(CpioError::Invalid("owner map line", line.to_string()));
+        };
+        if kind != "g" {
+            uids.insert(old, new);
+        }
+        if kind != "u" {
+            gids.insert(old, new);
+        }
+    }
+    Ok((uids, gids))
+}
+
+// Each line is HASH  ENTRY with a hex SHA-256 hash, as written by sha256sum. A '*' before
+// ENTRY (binary mode) is accepted and ignored.
+fn parse_manifest(content: &str) -> CpioResult<Vec<(Vec<u8>, String)>> {
+    let mut manifest = Vec::new();
+    for line in content.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some((Ok(hash), name)) = line
+            .split_once(' ')
+            .map(|(hash, name)| (parse_hex(hash), name.trim_start_matches([' ', '*'])))
+        else {
+            return Err(CpioError::Invalid("manifest line", line.to_string()));
+        };
+        if hash.len() != 32 || name.is_empty() {
+            return Err(CpioError::Invalid("manifest line", line.to_string()));
+        }
+        manifest.push((hash, norm_path(name)));
+    }
+    Ok(manifest)
+}
+
+fn parse_merge_strategy(s: &str) -> Result<MergeStrategy, String> {
+    match s {
+        "overwrite" => Ok(MergeStrategy::Overwrite),
+        "keep" => Ok(MergeStrategy::KeepExisting),
+        "error" => Ok(MergeStrategy::Error),
+        _ => Err(format!("unknown merge strategy {}", s)),
+    }
+}
+
+fn parse_diff_ignore(s: &str) -> Result<DiffIgnore, String> {
+    let mut ignore = DiffIgnore::default();
+    for attr in s.split(',') {
+        match attr {
+            "mode" => ignore.mode = true,
+            "uid" => ignore.uid = true,
+            "gid" => ignore.gid = true,
+            // Modification times are not kept when loading, so there is nothing to ignore
+            "mtime" => return Err("mtime is never compared".to_string()),
+            _ => return Err(format!("unknown attribute {}", attr)),
+        }
+    }
+    Ok(ignore)
+}
+
+fn parse_type(s: &str) -> Result<CpioType, String> {
+    CpioType::try_from(s)
+}
+
+fn parse_sort(s: &str) -> Result<ListSort, String> {
+    match s {
+        "name" => Ok(ListSort::Name),
+        "size" => Ok(ListSort::Size),
+        "mode" => Ok(ListSort::Mode),
+        _ => Err(format!("unknown sort key {}", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use super::*;
+    use test::Bencher;
+
+    fn dump_opts(format: CpioFormat) -> DumpOptions {
+        DumpOptions {
+            format,
+            mtime: 0,
+            inode: InodeMode::Keep,
+            level: None,
+            trailer: true,
+            progress: false,
+            prefix: None,
+            backup_suffix: None,
+        }
+    }
+
+    fn entry(ino: u32, mode: mode_t, data: &[u8]) -> Box<CpioEntry> {
+        Box::new(CpioEntry {
+            ino,
+            mode,
+            uid: 0,
+            gid: 0,
+            rdevmajor: 0,
+            rdevminor: 0,
+            data: data.to_vec().into(),
+            link: 0,
+        })
+    }
+
+    // Entries keep their inode numbers, so the reloaded archive compares equal
+    fn reload(cpio: &Cpio, format: CpioFormat) -> Cpio {
+        let buf = cpio.dump_to_vec(&dump_opts(format)).unwrap();
+        Cpio::load_from_data(&buf, &LoadOptions::default()).unwrap()
+    }
+
+    const FORMATS: [CpioFormat; 4] = [
+        CpioFormat::Newc,
+        CpioFormat::NewcCrc,
+        CpioFormat::Odc,
+        CpioFormat::Bin,
+    ];
+
+    #[test]
+    fn round_trip() {
+        let mut cpio = Cpio::new();
+        let mut null = entry(4, S_IFCHR | 0o666, b"");
+        null.rdevmajor = 1;
+        null.rdevminor = 3;
+        null.uid = 1000;
+        cpio.entries.insert("dev/null".into(), null);
+        cpio.entries
+            .insert("init".into(), entry(1, S_IFREG | 0o750, b"\x7fELF\0"));
+        cpio.entries
+            .insert("system".into(), entry(2, S_IFDIR | 0o755, b""));
+        cpio.entries
+            .insert("system/bin".into(), entry(3, S_IFLNK | 0o777, b"/bin"));
+        for format in FORMATS {
+            let loaded = reload(&cpio, format);
+            assert!(loaded == cpio, "{:?}", format);
+            assert_eq!(loaded.format(), Some(format));
+        }
+
+        // The same archive parsed from a stream, and across threads
+        let buf = cpio.dump_to_vec(&dump_opts(CpioFormat::Newc)).unwrap();
+        let mut input = StreamInput::new(buf.as_slice());
+        let streamed = Cpio::load_from_input(&mut input, &LoadOptions::default()).unwrap();
+        assert!(streamed == cpio);
+        assert_eq!(streamed.size(), buf.len());
+        let opts = LoadOptions {
+            threads: 3,
+            ..Default::default()
+        };
+        assert!(Cpio::load_from_data(&buf, &opts).unwrap() == cpio);
+    }
+
+    // Small deterministic generator, so that a failing seed can be reproduced
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self, n: u64) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) % n
+        }
+    }
+
+    #[test]
+    fn round_trip_random() {
+        let parts = ["a", "b", "init.rc", "sbin"];
+        let modes = [S_IFREG | 0o644, S_IFDIR | 0o755, S_IFLNK | 0o777];
+        for seed in 0..256 {
+            let mut rng = Lcg(seed);
+            let mut cpio = Cpio::new();
+            for ino in 1..=rng.next(16) as u32 {
+                let depth = rng.next(3) + 1;
+                let name = (0..depth)
+                    .map(|_| parts[rng.next(parts.len() as u64) as usize])
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let len = rng.next(600) as usize;
+                let data: Vec<u8> = (0..len).map(|_| rng.next(256) as u8).collect();
+                let mut e = entry(ino, modes[rng.next(3) as usize], &data);
+                e.uid = rng.next(5000) as uid_t;
+                e.gid = rng.next(5000) as gid_t;
+                cpio.entries.insert(name, e);
+            }
+            let format = FORMATS[seed as usize % FORMATS.len()];
+            assert!(reload(&cpio, format) == cpio, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn norm_path_matrix() {
+        let cases = [
+            ("", ""),
+            ("/", ""),
+            ("a", "a"),
+            ("a//b///c", "a/b/c"),
+            ("./a/./b", "a/b"),
+            ("a/b/", "a/b"),
+            ("/a/b", "a/b"),
+            ("..", ""),
+            ("../../a", "a"),
+            ("/../a/../b", "b"),
+            ("a/b/../../..", ""),
+            ("a\\b", "a\\b"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(norm_path(path), expected, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn norm_path_backslash() {
+        let cases = [
+            ("a\\b", "a/b"),
+            ("\\a\\\\b\\", "a/b"),
+            (".\\a/.\\b", "a/b"),
+            ("..\\..\\a", "a"),
+            ("a\\..\\b/c", "b/c"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(norm_path_sep(path, true), expected, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn backslash_only_applies_to_entry_paths() {
+        let mut cpio = Cpio::new();
+        cpio.backslash = true;
+        cpio.ln("..\\target", "dir\\link");
+        let entry = cpio.get("dir/link").unwrap();
+        assert_eq!(entry.data(), b"..\\target");
+    }
+
+    #[test]
+    fn mv_and_rm_drop_raw_names() {
+        let mut cpio = Cpio::new();
+        for name in ["a", "b", "d/c"] {
+            cpio.add_raw(0o644, name, b"").unwrap();
+            cpio.raw_names
+                .insert(name.to_string(), name.as_bytes().to_vec());
+        }
+        cpio.mv("a", "e", false).unwrap();
+        cpio.rm("b", false);
+        cpio.rm("d", true);
+        assert!(cpio.raw_names.is_empty());
+    }
+
+    #[test]
+    fn mv_rules() {
+        let mut cpio = Cpio::new();
+        cpio.add_raw(0o644, "a", b"a").unwrap();
+        cpio.add_raw(0o644, "b", b"b").unwrap();
+        let err = cpio.mv("a", "b", false);
+        assert!(matches!(err, Err(CpioError::Exists(name)) if name == "b"));
+        assert_eq!(cpio.entries["b"].data(), b"b");
+        cpio.mv("a", "b", true).unwrap();
+        assert!(!cpio.exists("a"));
+        assert_eq!(cpio.entries["b"].data(), b"a");
+
+        // Directories take their contents and raw names along
+        cpio.mkdir(0o755, "d", false).unwrap();
+        cpio.add_raw(0o644, "d/x\u{FFFD}", b"x").unwrap();
+        cpio.raw_names
+            .insert("d/x\u{FFFD}".into(), b"d/x\xff".to_vec());
+        cpio.mv("d", "e", false).unwrap();
+        let names: Vec<_> = cpio.entries.keys().map(String::as_str).collect();
+        assert_eq!(names, ["b", "e", "e/x\u{FFFD}"]);
+        assert_eq!(cpio.raw_names.len(), 1);
+        assert_eq!(cpio.raw_names["e/x\u{FFFD}"], b"e/x\xff");
+
+        // Not onto or into itself
+        for to in ["e", "e/sub"] {
+            let err = cpio.mv("e", to, true);
+            assert!(matches!(err, Err(CpioError::InvalidPath(_))));
+        }
+        assert!(cpio.exists("e/x\u{FFFD}"));
+    }
+
+    #[test]
+    fn sony_drops_raw_names() {
+        let mut cpio = Cpio::new();
+        for name in ["init", "init.real"] {
+            cpio.add_raw(0o750, name, name.as_bytes()).unwrap();
+            cpio.raw_names
+                .insert(name.to_string(), name.as_bytes().to_vec());
+        }
+        cpio.sony();
+        assert_eq!(cpio.entries["init"].data(), b"init.real");
+        assert!(cpio.raw_names.is_empty());
+    }
+
+    #[test]
+    fn prune_empty_drops_raw_names() {
+        let mut cpio = Cpio::new();
+        cpio.mkdir(0o755, "d", false).unwrap();
+        cpio.raw_names.insert("d".to_string(), b"d".to_vec());
+        cpio.prune_empty("");
+        assert!(cpio.entries.is_empty());
+        assert!(cpio.raw_names.is_empty());
+    }
+
+    #[test]
+    fn backup_keeps_raw_names() {
+        let mut stock = Cpio::new();
+        for name in ["a\u{FFFD}", "b\u{FFFD}"] {
+            stock.add_raw(0o644, name, b"stock").unwrap();
+            let raw = [&name.as_bytes()[..1], b"\xff".as_slice()].concat();
+            stock.raw_names.insert(name.to_string(), raw);
+        }
+        let path = env::temp_dir().join(format!("cpio-backup-{}", std::process::id()));
+        fs::write(
+            &path,
+            stock.dump_to_vec(&dump_opts(CpioFormat::Newc)).unwrap(),
+        )
+        .unwrap();
+        let mut cpio = stock.clone();
+        cpio.add_raw(0o644, "a\u{FFFD}", b"patched").unwrap();
+        let res = cpio.backup(&mut path.to_str().unwrap().to_string(), true);
+        fs::remove_file(&path).unwrap();
+        res.unwrap();
+        assert_eq!(cpio.raw_names[".backup/a\u{FFFD}"], b".backup/a\xff");
+        // Unchanged entries are not backed up
+        assert!(!cpio.raw_names.contains_key(".backup/b\u{FFFD}"));
+        let buf = cpio.dump_to_vec(&dump_opts(CpioFormat::Newc)).unwrap();
+        assert!(buf.windows(11).any(|w| w == b".backup/a\xff\0"));
+    }
+
+    #[test]
+    fn restore_moves_raw_names() {
+        let mut cpio = Cpio::new();
+        let names = [
+            ("a\u{FFFD}", b"a\xff".as_slice()),
+            (".backup/a\u{FFFD}", b".backup/a\xff".as_slice()),
+            (".backup/b\u{FFFD}", b".backup/b\xfe".as_slice()),
+        ];
+        for (name, raw) in names {
+            cpio.add_raw(0o644, name, name.as_bytes()).unwrap();
+            cpio.raw_names.insert(name.to_string(), raw.to_vec());
+        }
+        cpio.restore().unwrap();
+        assert_eq!(
+            cpio.entries["a\u{FFFD}"].data(),
+            ".backup/a\u{FFFD}".as_bytes()
+        );
+        assert_eq!(cpio.raw_names.len(), 2);
+        assert_eq!(cpio.raw_names["a\u{FFFD}"], b"a\xff");
+        assert_eq!(cpio.raw_names["b\u{FFFD}"], b"b\xfe");
+    }
+
+    #[test]
+    fn restore_from_source_keeps_other_entries() {
+        let mut stock = Cpio::new();
+        for name in ["init", "fstab.qcom", "a", "only"] {
+            stock.add_raw(0o644, name, name.as_bytes()).unwrap();
+        }
+        let path = env::temp_dir().join(format!("cpio-stock-{}", std::process::id()));
+        fs::write(
+            &path,
+            stock.dump_to_vec(&dump_opts(CpioFormat::Newc)).unwrap(),
+        )
+        .unwrap();
+
+        let mut cpio = stock.clone();
+        cpio.add_raw(0o750, "init", b"magiskinit").unwrap();
+        cpio.add_raw(0o644, "fstab.qcom", b"patched").unwrap();
+        cpio.mkdir(0o750, "overlay.d", false).unwrap();
+        cpio.add_raw(0o644, "overlay.d/x.rc", b"x").unwrap();
+        cpio.add_raw(0o644, "sbin/extra", b"extra").unwrap();
+        cpio.add_raw(0o644, ".backup/.rmlist", b"sbin/extra\0")
+            .unwrap();
+        cpio.add_raw(0o644, ".backup/.magisk", b"").unwrap();
+        // Not from Magisk, and only in the patched archive
+        cpio.add_raw(0o644, "user", b"user").unwrap();
+        cpio.rm("only", false);
+
+        let res = cpio.restore_from(&mut path.to_str().unwrap().to_string());
+        fs::remove_file(&path).unwrap();
+        res.unwrap();
+        let names: Vec<_> = cpio.entries.keys().map(String::as_str).collect();
+        assert_eq!(names, ["a", "fstab.qcom", "init", "user"]);
+        assert_eq!(cpio.get("init").unwrap().data(), b"init");
+        assert_eq!(cpio.get("fstab.qcom").unwrap().data(), b"fstab.qcom");
+    }
+
+    fn ls_names(cpio: &Cpio, args: &[&str]) -> Vec<String> {
+        let args = List::from_args(&["ls"], args).unwrap();
+        cpio.ls_entries(&args)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn ls_max_depth() {
+        let mut cpio = Cpio::new();
+        for dir in ["a", "a/b", "a/b/c"] {
+            cpio.mkdir(0o755, dir, false).unwrap();
+        }
+        cpio.add_raw(0o644, "a/b/c/d", b"").unwrap();
+        cpio.add_raw(0o644, "e", b"").unwrap();
+
+        assert!(ls_names(&cpio, &["--max-depth", "0"]).is_empty());
+        assert_eq!(ls_names(&cpio, &[]), ["a", "e"]);
+        assert_eq!(ls_names(&cpio, &["--max-depth", "1"]), ["a", "e"]);
+        assert_eq!(ls_names(&cpio, &["--max-depth", "2"]), ["a", "a/b", "e"]);
+        assert_eq!(
+            ls_names(&cpio, &["--max-depth", "4"]),
+            ["a", "a/b", "a/b/c", "a/b/c/d", "e"]
+        );
+        assert_eq!(
+            ls_names(&cpio, &["--max-depth", "5"]),
+            ls_names(&cpio, &["-r"])
+        );
+
+        // Levels are counted from PATH, which itself is at level 0
+        assert_eq!(ls_names(&cpio, &["--max-depth", "0", "a/b"]), ["a/b"]);
+        assert_eq!(
+            ls_names(&cpio, &["--max-depth", "1", "a/b"]),
+            ["a/b", "a/b/c"]
+        );
+        assert_eq!(
+            ls_names(&cpio, &["--max-depth", "2", "--relative", "a/b"]),
+            [".", "c", "c/d"]
+        );
+        assert_eq!(ls_names(&cpio, &["--max-depth", "0", "e"]), ["e"]);
+        // A sibling sharing the prefix is not under PATH
+        cpio.add_raw(0o644, "a/bc", b"").unwrap();
+        assert_eq!(ls_names(&cpio, &["--max-depth", "0", "a/b"]), ["a/b"]);
+    }
+
+    #[test]
+    fn add_size_limit() {
+        let path = env::temp_dir().join(format!("cpio-add-{}", std::process::id()));
+        let file = File::create(&path).unwrap();
+        // Sparse, so the size check is hit without writing anything to disk
+        let size = u64::from(u32::MAX) + 1;
+        file.set_len(size).unwrap();
+        let src = path.to_str().unwrap();
+        let mut cpio = Cpio::new();
+        cpio.add_raw(0o644, "big", b"old").unwrap();
+        let mut add = |max_size| {
+            cpio.add(
+                AddMode::Fixed(0o644),
+                "big",
+                &mut src.to_string(),
+                max_size,
+                false,
+            )
+        };
+
+        let default = add(None);
+        let explicit = add(Some(4096));
+        file.set_len(4096).unwrap();
+        let at_limit = add(Some(4096));
+        // Only read once needed, after the file grew past the limit
+        file.set_len(4097).unwrap();
+        let grown = cpio.load_files();
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(default, Err(CpioError::TooLarge(n)) if n == size));
+        assert!(matches!(explicit, Err(CpioError::TooLarge(n)) if n == size));
+        assert!(at_limit.is_ok());
+        assert!(matches!(grown, Err(CpioError::TooLarge(4097))));
+    }
+
+    #[test]
+    fn add_reads_file_when_needed() {
+        let path = env::temp_dir().join(format!("cpio-deferred-{}", std::process::id()));
+        fs::write(&path, b"old").unwrap();
+        let mut src = path.to_str().unwrap().to_string();
+        let mut cpio = Cpio::new();
+        let added = cpio.add(AddMode::Fixed(0o644), "a", &mut src, None, false);
+        // Nothing is read before the archive is written
+        fs::write(&path, b"new").unwrap();
+        let buf = cpio.dump_to_vec(&dump_opts(CpioFormat::Newc));
+        let deferred = cpio.entries["a"].data.clone();
+        let loaded = cpio.load_files();
+        fs::remove_file(&path).unwrap();
+
+        added.unwrap();
+        assert!(matches!(deferred, DataSource::FilePath { size: 3, .. }));
+        let reloaded = Cpio::load_from_data(&buf.unwrap(), &LoadOptions::default()).unwrap();
+        assert_eq!(reloaded.get("a").unwrap().data(), b"new");
+        loaded.unwrap();
+        assert_eq!(cpio.get("a").unwrap().data(), b"new");
+    }
+
+    #[test]
+    fn offsets_near_usize_max() {
+        let max = usize::MAX;
+        assert_eq!(align_after(5, 3, 4).unwrap(), 8);
+        assert_eq!(align_after(max - 3, 0, 4).unwrap(), max - 3);
+        assert!(matches!(
+            align_after(max - 3, 1, 4),
+            Err(CpioError::Truncated)
+        ));
+        assert!(matches!(align_after(max, 1, 2), Err(CpioError::Truncated)));
+        assert!(matches!(align_after(1, max, 1), Err(CpioError::Truncated)));
+
+        let data = [0u8; 8];
+        assert!(slice_at(&data, 8, 0).unwrap().is_empty());
+        assert!(matches!(slice_at(&data, 4, 5), Err(CpioError::Truncated)));
+        assert!(matches!(slice_at(&data, max, 1), Err(CpioError::Truncated)));
+        assert!(matches!(slice_at(&data, 1, max), Err(CpioError::Truncated)));
+
+        // Sizes from a header are never trusted to allocate or index
+        let mut input = StreamInput::new(&data[..]);
+        assert!(matches!(input.take(max), Err(CpioError::Truncated)));
+        let mut input = SliceInput::new(&data);
+        assert!(matches!(input.take(max), Err(CpioError::Truncated)));
+        input.skip(max).unwrap();
+        assert_eq!(input.offset(), data.len());
+    }
+
+    #[test]
+    fn huge_entry_sizes() {
+        let mut cpio = Cpio::new();
+        cpio.entries
+            .insert("a".into(), entry(1, S_IFREG | 0o644, b"data"));
+        let buf = cpio.dump_to_vec(&dump_opts(CpioFormat::Newc)).unwrap();
+        for field in [54, 94] {
+            // Replace the file or name size of the first entry
+            let mut buf = buf.clone();
+            buf[field..field + 8].copy_from_slice(b"ffffffff");
+            let loaded = Cpio::load_from_data(&buf, &LoadOptions::default());
+            assert!(matches!(loaded, Err(CpioError::Truncated)), "{}", field);
+            let mut input = StreamInput::new(buf.as_slice());
+            let loaded = Cpio::load_from_input(&mut input, &LoadOptions::default());
+            assert!(matches!(loaded, Err(CpioError::Truncated)), "{}", field);
+        }
+    }
+
+    #[test]
+    fn golden_old_formats() {
+        let mut cpio = Cpio::new();
+        cpio.entries
+            .insert("a".into(), entry(1, S_IFREG | 0o644, b"hi"));
+        let odc: &[u8] = b"\
+            070707000000000001100644000000000000000001000000000000000000000020000000000\
+            2a\0hi\
+            070707000000000002000755000000000000000001000000000000000000000130000000000\
+            0TRAILER!!!\0";
+        let bin: &[u8] = b"\
+            \xc7\x71\0\0\x01\0\xa4\x81\0\0\0\0\x01\0\0\0\0\0\0\0\x02\0\0\0\x02\0\
+            a\0hi\
+            \xc7\x71\0\0\x02\0\xed\x01\0\0\0\0\x01\0\0\0\0\0\0\0\x0b\0\0\0\0\0\
+            TRAILER!!!\0\0";
+        for (format, golden) in [(CpioFormat::Odc, odc), (CpioFormat::Bin, bin)] {
+            let opts = DumpOptions {
+                inode: InodeMode::Sequential,
+                ..dump_opts(format)
+            };
+            assert_eq!(cpio.dump_to_vec(&opts).unwrap(), golden, "{:?}", format);
+            let loaded = Cpio::load_from_data(golden, &LoadOptions::default()).unwrap();
+            assert!(loaded == cpio, "{:?}", format);
+            assert_eq!(loaded.format(), Some(format));
+        }
+    }
+
+    #[test]
+    fn ln_keeps_absolute_target() {
+        let mut cpio = Cpio::new();
+        cpio.ln("/system/bin/../bin//sh", "sbin/sh");
+        let entry = cpio.get("sbin/sh").unwrap();
+        assert_eq!(entry.mode & S_IFMT, S_IFLNK);
+        assert_eq!(entry.data(), b"/system/bin/../bin//sh");
+    }
+
+    #[test]
+    fn entry_accessors() {
+        let mut cpio = Cpio::new();
+        let mut null = entry(7, S_IFCHR | 0o666, b"");
+        null.rdevmajor = 1;
+        null.rdevminor = 3;
+        null.uid = 1000;
+        null.gid = 2000;
+        cpio.entries.insert("dev/null".into(), null);
+        cpio.entries
+            .insert("init".into(), entry(1, S_IFREG | 0o750, b"\x7fELF"));
+        let cpio = reload(&cpio, CpioFormat::Newc);
+
+        let names: Vec<_> = cpio.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["dev/null", "init"]);
+        let null = cpio.get("dev/null").unwrap();
+        assert_eq!(null.ino(), 7);
+        assert_eq!(null.mode(), S_IFCHR | 0o666);
+        assert_eq!(null.uid(), 1000);
+        assert_eq!(null.gid(), 2000);
+        assert_eq!(null.rdev(), (1, 3));
+        assert!(null.data().is_empty());
+        let init = cpio.get("/init").unwrap();
+        assert!(init.file_type() == Some(CpioType::Reg));
+        assert_eq!(init.mode() & 0o7777, 0o750);
+        assert_eq!(init.data(), b"\x7fELF");
+    }
+
+    #[test]
+    fn get_or_insert_dir_conflict() {
+        let mut cpio = Cpio::new();
+        cpio.add_raw(0o644, "a", b"a").unwrap();
+        assert!(matches!(
+            cpio.get_or_insert_dir("/a"),
+            Err(CpioError::InvalidEntry(path, _)) if path == "a"
+        ));
+        assert!(cpio.mkdir(0o700, "a", false).is_err());
+        assert_eq!(cpio.get("a").unwrap().data(), b"a");
+
+        assert_eq!(cpio.get_or_insert_dir("d").unwrap().mode(), S_IFDIR | 0o755);
+        cpio.mkdir(0o700, "d", false).unwrap();
+        assert_eq!(cpio.get_or_insert_dir("d").unwrap().mode(), S_IFDIR | 0o700);
+    }
+
+    #[test]
+    fn data_beyond_trailer_scan() {
+        let mut cpio = Cpio::new();
+        cpio.entries
+            .insert("a".into(), entry(1, S_IFREG | 0o644, b"hi"));
+        let mut buf = cpio.dump_to_vec(&dump_opts(CpioFormat::Newc)).unwrap();
+        buf.extend_from_slice(&[0; 16]);
+        buf.extend_from_slice(&[0xaa; 100]);
+        let opts = LoadOptions {
+            limits: CpioLimits::default().trailer_scan(10),
+            ..Default::default()
+        };
+        let loaded = Cpio::load_from_data(&buf, &opts).unwrap();
+        assert!(loaded == cpio);
+        assert_eq!(loaded.garbage(), 100);
+        let mut input = StreamInput::new(buf.as_slice());
+        let streamed = Cpio::load_from_input(&mut input, &opts).unwrap();
+        assert_eq!(streamed.garbage(), 100);
+        assert_eq!(streamed.size(), buf.len());
+    }
+
+    #[test]
+    fn garbage_after_bin_trailer() {
+        let mut cpio = Cpio::new();
+        cpio.entries
+            .insert("a".into(), entry(1, S_IFREG | 0o644, b"hi"));
+        let archive = cpio.dump_to_vec(&dump_opts(CpioFormat::Bin)).unwrap();
+        let mut buf = archive.clone();
+        buf.extend_from_slice(b"\0\0garbage");
+        let loaded = Cpio::load_from_data(&buf, &LoadOptions::default()).unwrap();
+        assert!(loaded == cpio);
+        assert_eq!(loaded.garbage(), 7);
+
+        // A concatenated binary archive is still loaded
+        let mut other = Cpio::new();
+        other
+            .entries
+            .insert("b".into(), entry(2, S_IFREG | 0o644, b"ho"));
+        let mut buf = archive;
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend(other.dump_to_vec(&dump_opts(CpioFormat::Bin)).unwrap());
+        let loaded = Cpio::load_from_data(&buf, &LoadOptions::default()).unwrap();
+        assert_eq!(loaded.entries.len(), 2);
+        assert_eq!(loaded.garbage(), 0);
+    }
+
+    #[test]
+    fn threaded_multi_segment_load() {
+        let segment = |n: usize, data: &[u8]| {
+            let mut cpio = Cpio::new();
+            for i in 0..10 {
+                let name = format!("s{}/{}", n, i);
+                cpio.entries
+                    .insert(name, entry(i + 1, S_IFREG | 0o644, data));
+            }
+            cpio.entries
+                .insert("dup".into(), entry(100, S_IFREG | 0o644, data));
+            cpio
+        };
+        let segments = [
+            segment(0, b"first"),
+            segment(1, b"second"),
+            segment(2, b"third"),
+        ];
+        // Garbage before the second archive, zero padding before the third, and data beyond
+        // the trailer scan after it
+        let mut buf = segments[0]
+            .dump_to_vec(&dump_opts(CpioFormat::Newc))
+            .unwrap();
+        buf.extend_from_slice(b"junk");
+        buf.extend(
+            segments[1]
+                .dump_to_vec(&dump_opts(CpioFormat::Odc))
+                .unwrap(),
+        );
+        buf.extend_from_slice(&[0; 16]);
+        buf.extend(
+            segments[2]
+                .dump_to_vec(&dump_opts(CpioFormat::Newc))
+                .unwrap(),
+        );
+        buf.extend_from_slice(&[0; 16]);
+        buf.extend_from_slice(&[0xaa; 100]);
+
+        let mut expected = Cpio::new();
+        for cpio in segments {
+            expected.entries.extend(cpio.entries);
+        }
+        assert_eq!(expected.entries["dup"].data(), b"third");
+        for threads in [1, 2, 3, 8] {
+            let opts = LoadOptions {
+                threads,
+                limits: CpioLimits::default().trailer_scan(10),
+                ..Default::default()
+            };
+            let loaded = Cpio::load_from_data(&buf, &opts).unwrap();
+            assert!(loaded == expected, "{} threads", threads);
+            assert_eq!(loaded.garbage(), 104);
+            let mut input = StreamInput::new(buf.as_slice());
+            let streamed = Cpio::load_from_input(&mut input, &opts).unwrap();
+            assert!(streamed == expected, "{} threads streamed", threads);
+            assert_eq!(streamed.garbage(), 104);
+        }
+    }
+
+    #[test]
+    fn merge_collision_leaves_archive_untouched() {
+        let mut cpio = Cpio::new();
+        cpio.entries
+            .insert("b".into(), entry(1, S_IFREG | 0o644, b"ours"));
+        cpio.entries
+            .insert("d".into(), entry(2, S_IFDIR | 0o755, b""));
+        let mut other = Cpio::new();
+        other
+            .entries
+            .insert("a".into(), entry(3, S_IFREG | 0o644, b"new"));
+        other
+            .entries
+            .insert("b".into(), entry(4, S_IFREG | 0o644, b"theirs"));
+        other
+            .entries
+            .insert("d".into(), entry(5, S_IFDIR | 0o755, b""));
+        let orig = cpio.clone();
+        let err = cpio.merge(other.clone(), MergeStrategy::Error);
+        assert!(matches!(err, Err(CpioError::Exists(name)) if name == "b"));
+        assert!(cpio == orig);
+
+        // Directories do not collide
+        other.entries.remove("b");
+        cpio.merge(other, MergeStrategy::Error).unwrap();
+        assert_eq!(cpio.entries.len(), 3);
+        assert_eq!(cpio.entries["d"].ino, 2);
+    }
+
+    #[test]
+    fn file_buffer_too_large() {
+        assert!(matches!(
+            file_buffer(u64::MAX),
+            Err(CpioError::TooLarge(u64::MAX))
+        ));
+        assert!(file_buffer(16).unwrap().capacity() >= 16);
+    }
+
+    #[test]
+    fn position_flags_rejected() {
+        let mut cpio = Cpio::new();
+        cpio.add_raw(0o644, "a", b"").unwrap();
+        let mut file = "ramdisk.cpio".to_string();
+        let file = Utf8CStr::from_string(&mut file);
+        for args in [
+            &["mv", "--after", "init", "a", "b"][..],
+            &["mv", "--before", "init", "a", "b"],
+            &["add", "--after", "init", "644", "c", "/dev/null"],
+            &["add", "--before", "init", "644", "c", "/dev/null"],
+        ] {
+            let mut cmd =
+                CpioCommand::from_args(&["magiskboot", "cpio", "ramdisk.cpio"], args).unwrap();
+            let res = exec_action(&mut cpio, file, &mut cmd.action, false);
+            assert!(matches!(res, Err(CpioError::Unsupported(_))), "{:?}", args);
+        }
+        assert_eq!(cpio.entries.keys().collect::<Vec<_>>(), ["a"]);
+    }
+
+    #[test]
+    fn read_only_actions() {
+        let action = |args: &[&str]| {
+            CpioCommand::from_args(&["magiskboot", "cpio", "ramdisk.cpio"], args)
+                .unwrap()
+                .action
+        };
+        for args in [
+            &["ls"][..],
+            &["stat", "init"],
+            &["rm", "--dry-run", "init"],
+            &["patch", "--print-plan"],
+            &["extract"],
+        ] {
+            assert!(!action(args).mutates(), "{:?}", args);
+        }
+        for args in [&["rm", "init"][..], &["patch"], &["mkdir", "755", "a"]] {
+            assert!(action(args).mutates(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn compressed_file_size() {
+        let mut cpio = Cpio::new();
+        cpio.entries
+            .insert("a".into(), entry(1, S_IFREG | 0o644, &[b'a'; 4096]));
+        let buf = cpio.dump_to_vec(&dump_opts(CpioFormat::Newc)).unwrap();
+        let mut compressed = Vec::new();
+        assert!(CpioCompression::Xz.compress(&buf, 9, &mut compressed));
+        let mut path = env::temp_dir()
+            .join(format!("cpio-file-size-{}.cpio", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        fs::write(&path, &compressed).unwrap();
+        let loaded =
+            Cpio::load_from_file(Utf8CStr::from_string(&mut path), &LoadOptions::default());
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.compression(), Some(CpioCompression::Xz));
+        assert_eq!(loaded.size(), buf.len());
+        assert_eq!(loaded.file_size(), compressed.len());
+        assert!(loaded.file_size() < loaded.size());
+
+        // Both are the same without compression
+        let loaded = Cpio::load_from_data(&buf, &LoadOptions::default()).unwrap();
+        assert_eq!(loaded.file_size(), buf.len());
+    }
+
+    #[test]
+    fn forced_compression() {
+        let mut cpio = Cpio::new();
+        cpio.add_raw(0o644, "a", b"a").unwrap();
+        let buf = cpio.dump_to_vec(&dump_opts(CpioFormat::Newc)).unwrap();
+        let mut compressed = Vec::new();
+        assert!(CpioCompression::Lzma.compress(&buf, 6, &mut compressed));
+        let mut path = env::temp_dir()
+            .join(format!("cpio-forced-{}.cpio", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        fs::write(&path, &compressed).unwrap();
+        let path = Utf8CStr::from_string(&mut path);
+        // Raw LZMA is only known through the options, never detected
+        let detected = Cpio::load_from_file(path, &LoadOptions::default());
+        let opts = LoadOptions {
+            compression: Some(CpioCompression::Lzma),
+            ..Default::default()
+        };
+        let forced = Cpio::load_from_file(path, &opts);
+        fs::remove_file(path.as_str()).unwrap();
+        assert!(detected.is_err());
+        let forced = forced.unwrap();
+        assert_eq!(forced.compression(), Some(CpioCompression::Lzma));
+        assert!(forced.exists("a"));
+    }
+
+    // "bin/a" and "bin/b" hardlinked with inode ino, "bin/c" on its own
+    fn linked(ino: u32) -> Cpio {
+        let mut cpio = Cpio::new();
+        for name in ["bin/a", "bin/b"] {
+            let mut e = entry(ino, S_IFREG | 0o755, b"x");
+            e.link = 1;
+            cpio.entries.insert(name.into(), e);
+        }
+        cpio.entries
+            .insert("bin/c".into(), entry(ino + 1, S_IFREG | 0o755, b"x"));
+        cpio
+    }
+
+    fn path_opts() -> DumpOptions {
+        DumpOptions {
+            inode: InodeMode::Path,
+            ..dump_opts(CpioFormat::Newc)
+        }
+    }
+
+    #[test]
+    fn path_inodes_keep_hardlinks() {
+        let cpio = linked(5);
+        let buf = cpio.dump_to_vec(&path_opts()).unwrap();
+        let loaded = Cpio::load_from_data(&buf, &LoadOptions::default()).unwrap();
+        let e = |name: &str| &loaded.entries[name];
+        assert_eq!(e("bin/a").ino, e("bin/b").ino);
+        assert_ne!(e("bin/a").ino, e("bin/c").ino);
+        assert_eq!(e("bin/a").ino, fnv1a(b"bin/a"));
+        // Written with nlink 2, so the group survives a reload
+        assert_ne!(e("bin/a").link, 0);
+        assert_eq!(e("bin/a").link, e("bin/b").link);
+        assert_eq!(e("bin/c").link, 0);
+        assert_eq!(loaded.dump_to_vec(&path_opts()).unwrap(), buf);
+
+        // The numbers do not depend on the original ones
+        let mut renumbered = cpio.clone();
+        for e in renumbered.entries.values_mut() {
+            e.ino += 100;
+        }
+        assert_eq!(renumbered.dump_to_vec(&path_opts()).unwrap(), buf);
+
+        // Replacing the data of a member takes it out of its group
+        let mut replaced = loaded.clone();
+        replaced.add_raw(0o755, "bin/b", b"y").unwrap();
+        let buf = replaced.dump_to_vec(&path_opts()).unwrap();
+        let reloaded = Cpio::load_from_data(&buf, &LoadOptions::default()).unwrap();
+        assert_ne!(reloaded.entries["bin/a"].ino, reloaded.entries["bin/b"].ino);
+        assert_eq!(reloaded.entries["bin/a"].link, 0);
+    }
+
+    #[test]
+    fn hardlinks_within_segments() {
+        // Each concatenated archive restarts its inode numbers
+        let mut second = Cpio::new();
+        for name in ["lib/a", "lib/b"] {
+            let mut e = entry(5, S_IFREG | 0o755, b"y");
+            e.link = 1;
+            second.entries.insert(name.into(), e);
+        }
+        let keep = dump_opts(CpioFormat::Newc);
+        let mut buf = linked(5).dump_to_vec(&keep).unwrap();
+        buf.extend(second.dump_to_vec(&keep).unwrap());
+        let loaded = Cpio::load_from_data(&buf, &LoadOptions::default()).unwrap();
+        let link = |name: &str| loaded.entries[name].link;
+        assert_eq!(link("bin/a"), link("bin/b"));
+        assert_eq!(link("lib/a"), link("lib/b"));
+        assert_ne!(link("bin/a"), link("lib/a"));
+
+        let buf = loaded.dump_to_vec(&path_opts()).unwrap();
+        let loaded = Cpio::load_from_data(&buf, &LoadOptions::default()).unwrap();
+        let ino = |name: &str| loaded.entries[name].ino;
+        assert_eq!(ino("bin/a"), ino("bin/b"));
+        assert_eq!(ino("lib/a"), ino("lib/b"));
+        assert_ne!(ino("bin/a"), ino("lib/a"));
+    }
+
+    #[test]
+    fn merge_drops_hardlink_groups() {
+        let mut cpio = linked(5);
+        let mut other = Cpio::new();
+        let mut e = entry(5, S_IFREG | 0o755, b"y");
+        e.link = 1;
+        other.entries.insert("lib/a".into(), e);
+        cpio.copy_from(&other, "lib/a", Some("lib/b")).unwrap();
+        cpio.merge(other, MergeStrategy::Error).unwrap();
+        assert_eq!(cpio.entries["lib/a"].link, 0);
+        assert_eq!(cpio.entries["lib/b"].link, 0);
+
+        let buf = cpio.dump_to_vec(&path_opts()).unwrap();
+        let loaded = Cpio::load_from_data(&buf, &LoadOptions::default()).unwrap();
+        let ino = |name: &str| loaded.entries[name].ino;
+        assert_eq!(ino("bin/a"), ino("bin/b"));
+        assert_ne!(ino("bin/a"), ino("lib/a"));
+        assert_ne!(ino("lib/a"), ino("lib/b"));
+        assert_eq!(loaded.entries["lib/a"].link, 0);
+    }
+
+    #[test]
+    fn old_formats_fit_inodes() {
+        let cpio = linked(0x12345678);
+        let dump = |format, inode| {
+            let opts = DumpOptions {
+                inode,
+                ..dump_opts(format)
+            };
+            cpio.dump_to_vec(&opts).unwrap()
+        };
+        let trailer =
+            "0707070000000000040007550000000000000000010000000000000000000001300000000000TRAILER!!!\0";
+        // Keep renumbers in order, as the original numbers do not fit
+        let keep = [
+            "0707070000000000011007550000000000000000020000000000000000000000600000000001bin/a\0x",
+            "0707070000000000011007550000000000000000020000000000000000000000600000000001bin/b\0x",
+            "0707070000000000031007550000000000000000010000000000000000000000600000000001bin/c\0x",
+            trailer,
+        ];
+        assert_eq!(
+            dump(CpioFormat::Odc, InodeMode::Keep),
+            keep.concat().as_bytes()
+        );
+        let hash = [
+            "0707070000000502071007550000000000000000010000000000000000000000600000000001bin/a\0x",
+            "0707070000000502071007550000000000000000010000000000000000000000600000000001bin/b\0x",
+            "0707070000000502071007550000000000000000010000000000000000000000600000000001bin/c\0x",
+            trailer,
+        ];
+        assert_eq!(
+            dump(CpioFormat::Odc, InodeMode::Hash),
+            hash.concat().as_bytes()
+        );
+        let path = [
+            "0707070000003346061007550000000000000000020000000000000000000000600000000001bin/a\0x",
+            "0707070000003346061007550000000000000000020000000000000000000000600000000001bin/b\0x",
+            "0707070000003331401007550000000000000000010000000000000000000000600000000001bin/c\0x",
+            trailer,
+        ];
+        assert_eq!(
+            dump(CpioFormat::Odc, InodeMode::Path),
+            path.concat().as_bytes()
+        );
+
+        for inode in [InodeMode::Keep, InodeMode::Hash, InodeMode::Path] {
+            let buf = dump(CpioFormat::Bin, inode);
+            let loaded = Cpio::load_from_data(&buf, &LoadOptions::default()).unwrap();
+            let e = |name: &str| &loaded.entries[name];
+            assert!(e("bin/a").ino < 1 << 16);
+            assert_eq!(e("bin/a").ino, e("bin/b").ino);
+        }
+
+        // Numbers that fit are kept as they are
+        let small = linked(5);
+        let opts = dump_opts(CpioFormat::Odc);
+        let buf = small.dump_to_vec(&opts).unwrap();
+        let loaded = Cpio::load_from_data(&buf, &LoadOptions::default()).unwrap();
+        assert_eq!(loaded.entries["bin/a"].ino, 5);
+        assert_eq!(loaded.entries["bin/c"].ino, 6);
+    }
+
+    #[test]
+    fn fields_checked_against_width() {
+        let check = |format, e: &CpioEntry, ino| {
+            let mut pos = 0;
+            let err = write_entry(
+                &mut Vec::<u8>::new(),
+                &dump_opts(format),
+                b"a",
+                e,
+                ino,
+                1,
+                &mut pos,
+            );
+            match err {
+                Err(CpioError::FieldOverflow(name, field, _)) => (name, field),
+                _ => panic!("{format} dump did not overflow"),
+            }
+        };
+        let e = entry(1, S_IFREG | 0o644, b"");
+        assert_eq!(check(CpioFormat::Newc, &e, 1 << 32), ("a".into(), "ino"));
+        assert_eq!(check(CpioFormat::Odc, &e, 1 << 18), ("a".into(), "ino"));
+        assert_eq!(check(CpioFormat::Bin, &e, 1 << 16), ("a".into(), "ino"));
+
+        // Ids and device numbers loaded from newc may not fit the old formats
+        let mut e = entry(1, S_IFCHR | 0o644, b"");
+        e.uid = 1 << 18;
+        assert_eq!(check(CpioFormat::Odc, &e, 1), ("a".into(), "uid"));
+        e.uid = 0;
+        e.gid = 1 << 16;
+        assert_eq!(check(CpioFormat::Bin, &e, 1), ("a".into(), "gid"));
+        e.gid = 0;
+        e.rdevmajor = 0x100;
+        assert_eq!(check(CpioFormat::Odc, &e, 1), ("a".into(), "rdev"));
+        assert_eq!(check(CpioFormat::Bin, &e, 1), ("a".into(), "rdev"));
+    }
+
+    #[test]
+    fn dump_offsets_checked() {
+        let e = entry(1, S_IFREG | 0o644, b"abc");
+        for format in FORMATS {
+            let mut out = Vec::new();
+            let mut pos = 0;
+            write_entry(&mut out, &dump_opts(format), b"a", &e, 1, 1, &mut pos).unwrap();
+            assert_eq!(pos, out.len());
+            assert_eq!(pos % format.align(), 0);
+
+            let mut pos = usize::MAX - 8;
+            let err = write_entry(&mut out, &dump_opts(format), b"a", &e, 1, 1, &mut pos);
+            assert!(matches!(err, Err(CpioError::Truncated)), "{format}");
+        }
+    }
+
+    #[test]
+    fn json_output() {
+        let mut cpio = Cpio::new();
+        cpio.add_raw(0o644, "a", b"abc").unwrap();
+        cpio.add_raw(0o600, "b", b"").unwrap();
+        cpio.ln("/system/bin/sh", "sh");
+        let mut other = cpio.clone();
+        other.entries.remove("a");
+        other.add_raw(0o644, "b", b"x").unwrap();
+        other.add_raw(0o644, "c\"", b"").unwrap();
+
+        let diff = cpio.diff(&other, DiffIgnore::default(), "");
+        assert_eq!(
+            diff.to_json().to_string(),
+            r#"{"added":["c\""],"removed":["a"],"modified":[{"name":"b","attrs":["mode","data"]}]}"#
+        );
+        assert_eq!(
+            cpio.info_json().to_string(),
+            r#"{"format":null,"compression":null,"size":0,"file_size":0,"entries":3,"garbage":0}"#
+        );
+        assert_eq!(
+            cpio.entries["a"].to_json("a").to_string(),
+            r#"{"name":"a","type":"-","mode":420,"uid":0,"gid":0,"rdev":[0,0],"size":3,"ino":0,"target":null}"#
+        );
+        assert_eq!(
+            cpio.entries["sh"].to_json("sh").to_string(),
+            r#"{"name":"sh","type":"l","mode":0,"uid":0,"gid":0,"rdev":[0,0],"size":14,"ino":0,"target":"/system/bin/sh"}"#
+        );
+    }
+
+    #[test]
+    fn replace_reuses_allocations() {
+        let mut cpio = Cpio::new();
+        cpio.add_raw(0o644, "a", &[0; 4096]).unwrap();
+        let entry: *const CpioEntry = &*cpio.entries["a"];
+        let data = cpio.entries["a"].data.as_ptr();
+        for i in 0..16 {
+            cpio.add_raw(0o644, "a", &[i; 4096]).unwrap();
+        }
+        assert_eq!(&*cpio.entries["a"] as *const CpioEntry, entry);
+        assert_eq!(cpio.entries["a"].data.as_ptr(), data);
+    }
+
+    // A batch of edits to the same entries, run with cargo bench. In place edits allocate
+    // nothing once the buffers are large enough; reinserting allocates an entry and its data
+    // on every edit.
+    const BATCH_NAMES: usize = 16;
+    const BATCH_EDITS: usize = 1024;
+
+    fn batch_names() -> Vec<String> {
+        (0..BATCH_NAMES).map(|i| format!("overlay/{}", i)).collect()
+    }
+
+    #[bench]
+    fn batch_edits_in_place(b: &mut Bencher) {
+        let names = batch_names();
+        let mut cpio = Cpio::new();
+        let data = [0u8; 4096];
+        b.iter(|| {
+            for name in names.iter().cycle().take(BATCH_EDITS) {
+                let entry = cpio.get_or_insert(name);
+                entry.mode = S_IFREG | 0o644;
+                entry.data.clear();
+                entry.data.extend_from_slice(&data);
+            }
+        });
+    }
+
+    #[bench]
+    fn batch_edits_reinsert(b: &mut Bencher) {
+        let names = batch_names();
+        let mut cpio = Cpio::new();
+        let data = [0u8; 4096];
+        b.iter(|| {
+            for name in names.iter().cycle().take(BATCH_EDITS) {
+                cpio.entries.remove(name);
+                cpio.entries
+                    .insert(name.clone(), entry(0, S_IFREG | 0o644, &data));
+            }
+        });
+    }
+
+    // A synthetic archive the size of a large vendor ramdisk, run with cargo bench
+    fn large_archive() -> Vec<u8> {
+        let mut cpio = Cpio::new();
+        for i in 0..100_000 {
+            let name = format!("vendor/lib/{}/lib{}.so", i % 100, i);
+            cpio.entries
+                .insert(name, entry(i + 1, S_IFREG | 0o644, &[i as u8; 256]));
+        }
+        cpio.dump_to_vec(&dump_opts(CpioFormat::Newc)).unwrap()
+    }
+
+    fn bench_load(b: &mut Bencher, threads: usize) {
+        let buf = large_archive();
+        let opts = LoadOptions {
+            threads,
+            ..Default::default()
+        };
+        b.iter(|| Cpio::load_from_data(&buf, &opts).unwrap());
+    }
+
+    #[bench]
+    fn load_large_archive(b: &mut Bencher) {
+        bench_load(b, 1);
+    }
+
+    #[bench]
+    fn load_large_archive_threads(b: &mut Bencher) {
+        bench_load(b, 4);
+    }
 }
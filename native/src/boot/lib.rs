@@ -1,8 +1,12 @@
 #![feature(format_args_nl)]
 #![feature(btree_extract_if)]
-#![feature(iter_intersperse)]
+#![cfg_attr(test, feature(test))]
 
 pub use base;
+pub use cpio::{
+    Cpio, CpioCache, CpioCompression, CpioEntry, CpioError, CpioFormat, CpioLimits, CpioResult,
+    CpioType, MergeStrategy,
+};
 use cpio::cpio_commands;
 use dtb::dtb_commands;
 use patch::hexpatch;
@@ -12,6 +16,7 @@ use std::env;
 
 mod cpio;
 mod dtb;
+mod json;
 mod patch;
 mod payload;
 // Suppress warnings in generated code
@@ -24,8 +29,12 @@ pub mod ffi {
     unsafe extern "C++" {
         include!("compress.hpp");
         fn decompress(buf: &[u8], fd: i32) -> bool;
-        fn xz(buf: &[u8], out: &mut Vec<u8>) -> bool;
+        fn xz(buf: &[u8], level: u32, out: &mut Vec<u8>) -> bool;
         fn unxz(buf: &[u8], out: &mut Vec<u8>) -> bool;
+        fn lzma(buf: &[u8], level: u32, out: &mut Vec<u8>) -> bool;
+        fn unlzma(buf: &[u8], out: &mut Vec<u8>) -> bool;
+        fn unxz_fd(buf: &[u8], fd: i32) -> bool;
+        fn unlzma_fd(buf: &[u8], fd: i32) -> bool;
 
         include!("bootimg.hpp");
         #[cxx_name = "boot_img"]
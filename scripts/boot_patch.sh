@@ -119,7 +119,7 @@ else
   STATUS=0
   SKIP_BACKUP="#"
 fi
-case $STATUS in
+case $((STATUS & 3)) in
   0 )
     # Stock boot
     ui_print "- Stock boot image detected"